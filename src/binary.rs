@@ -0,0 +1,94 @@
+use colored::*;
+use std::io;
+use std::io::Write;
+
+const HEX_WIDTH: usize = 16;
+
+/// Guesses a MIME type from well-known magic numbers at the start of
+/// `content`. This only has to be good enough to tell a human what they're
+/// looking at.
+pub fn detect_mime_type(content: &[u8]) -> &'static str {
+  let signatures: &[(usize, &[u8], &str)] = &[
+    (0, b"\x89PNG\r\n\x1a\n", "image/png"),
+    (0, b"\xff\xd8\xff", "image/jpeg"),
+    (0, b"GIF87a", "image/gif"),
+    (0, b"GIF89a", "image/gif"),
+    (0, b"BM", "image/bmp"),
+    (0, b"\x00\x00\x01\x00", "image/x-icon"),
+    (8, b"WEBP", "image/webp"),
+    (8, b"WAVE", "audio/wav"),
+    (0, b"ID3", "audio/mpeg"),
+    (0, b"OggS", "audio/ogg"),
+    (0, b"fLaC", "audio/flac"),
+    (4, b"ftyp", "video/mp4"),
+    (0, b"%PDF-", "application/pdf"),
+    (0, b"PK\x03\x04", "application/zip"),
+    (0, b"\x1f\x8b", "application/gzip"),
+    (0, b"BZh", "application/x-bzip2"),
+    (0, b"\xfd7zXZ\x00", "application/x-xz"),
+    (0, b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (257, b"ustar", "application/x-tar"),
+    (0, b"\x7fELF", "application/x-elf"),
+    (0, b"\xcf\xfa\xed\xfe", "application/x-mach-binary"),
+    (0, b"MZ", "application/x-msdownload"),
+    (0, b"\x00asm", "application/wasm"),
+    (0, b"\xca\xfe\xba\xbe", "application/java-vm"),
+    (0, b"SQLite format 3\x00", "application/x-sqlite3"),
+    (0, b"wOFF", "font/woff"),
+    (0, b"wOF2", "font/woff2"),
+  ];
+
+  for (offset, magic, mime) in signatures {
+    if content.len() >= offset + magic.len() && &content[*offset..offset + magic.len()] == *magic {
+      return mime;
+    }
+  }
+
+  "application/octet-stream"
+}
+
+fn colorize_byte(byte: u8, text: &str) -> ColoredString {
+  match byte {
+    0 => text.bright_black(),
+    b'\t' | b'\n' | b'\r' | b' ' => text.green(),
+    0x21..=0x7e => text.cyan(),
+    0x01..=0x1f | 0x7f => text.magenta(),
+    _ => text.yellow(),
+  }
+}
+
+/// Prints `content` as offset, hex bytes and an ASCII gutter, sixteen bytes to
+/// a line, with bytes colored by class so that structure stands out.
+pub fn print_hexdump(content: &[u8]) -> io::Result<()> {
+  let stdout = io::stdout();
+  let mut out = stdout.lock();
+
+  for (line_number, chunk) in content.chunks(HEX_WIDTH).enumerate() {
+    let offset = format!("{:08x}", line_number * HEX_WIDTH);
+    write!(out, "{} ", offset.bright_black())?;
+
+    for column in 0..HEX_WIDTH {
+      if column == HEX_WIDTH / 2 {
+        write!(out, " ")?;
+      }
+      match chunk.get(column) {
+        Some(&byte) => write!(out, "{} ", colorize_byte(byte, &format!("{:02x}", byte)))?,
+        None => write!(out, "   ")?,
+      }
+    }
+
+    write!(out, "{}", "│".bright_black())?;
+    for &byte in chunk {
+      let shown = if byte.is_ascii_graphic() || byte == b' ' {
+        (byte as char).to_string()
+      } else {
+        ".".to_string()
+      };
+      write!(out, "{}", colorize_byte(byte, &shown))?;
+    }
+    write!(out, "{:width$}", "", width = HEX_WIDTH - chunk.len())?;
+    writeln!(out, "{}", "│".bright_black())?;
+  }
+
+  Ok(())
+}
@@ -0,0 +1,204 @@
+use super::relative_time;
+use super::status::describe_distance;
+use super::MilkRepo;
+use colored::*;
+use failure::Error;
+use failure::ResultExt;
+use git2::Branch;
+use git2::BranchType;
+use git2::Commit;
+use git2::Oid;
+use git2::Repository;
+
+/// The remote branch a local branch tracks, and how far apart they are.
+pub struct Upstream {
+  pub name: String,
+  pub distance: Option<(usize, usize)>,
+}
+
+/// Everything `branch ls` shows about one branch.
+pub struct BranchSummary<'repo> {
+  pub name: String,
+  pub is_head: bool,
+  pub is_remote: bool,
+  pub commit: Commit<'repo>,
+  pub upstream: Option<Upstream>,
+}
+
+fn find_upstream(repo: &Repository, branch: &Branch, tip: Oid) -> Result<Option<Upstream>, Error> {
+  if let Ok(upstream) = branch.upstream() {
+    let name = upstream
+      .name()
+      .ok()
+      .flatten()
+      .unwrap_or("[???]")
+      .to_string();
+
+    let distance = match upstream.get().target() {
+      Some(upstream_tip) => Some(
+        repo
+          .graph_ahead_behind(tip, upstream_tip)
+          .with_context(|_| "couldn't compare with upstream")?,
+      ),
+      None => None,
+    };
+
+    return Ok(Some(Upstream { name, distance }));
+  }
+
+  // the upstream can still be configured after the remote branch is deleted,
+  // in which case there's nothing to count against
+  let refname = branch.get().name().unwrap_or("");
+  match repo.branch_upstream_name(refname) {
+    Ok(name) => {
+      let name = name.as_str().unwrap_or("[???]");
+      Ok(Some(Upstream {
+        name: name.trim_start_matches("refs/remotes/").to_string(),
+        distance: None,
+      }))
+    }
+    Err(_) => Ok(None),
+  }
+}
+
+/// Describes what `branch` tracks and how far apart they are, or None if it
+/// doesn't track anything.
+pub fn describe_tracking(repo: &Repository, branch: &Branch) -> Result<Option<String>, Error> {
+  let tip = branch
+    .get()
+    .peel_to_commit()
+    .with_context(|_| "couldn't find branch tip")?
+    .id();
+
+  Ok(
+    find_upstream(repo, branch, tip)?.map(|upstream| match upstream.distance {
+      Some((ahead, behind)) => format!(
+        "{} {}",
+        upstream.name.cyan(),
+        describe_distance(ahead, behind)
+      ),
+      None => format!("{} {}", upstream.name.cyan(), "gone".red()),
+    }),
+  )
+}
+
+/// Gathers the local branches, and the remote ones too if asked, along with
+/// their tip commits and upstreams.
+pub fn collect_branches(
+  repo: &Repository,
+  include_remote: bool,
+) -> Result<Vec<BranchSummary<'_>>, Error> {
+  let filter = if include_remote {
+    None
+  } else {
+    Some(BranchType::Local)
+  };
+
+  let branches = repo
+    .branches(filter)
+    .with_context(|_| "couldn't iterate branches")?;
+
+  let mut summaries = Vec::new();
+  for branch in branches {
+    let (branch, typ) = branch.with_context(|_| "couldn't identify branch")?;
+    let name = branch
+      .name()
+      .with_context(|_| "couldn't identify branch name")?
+      .unwrap_or("[branch name is invalid utf8]")
+      .to_string();
+    let commit = branch
+      .get()
+      .peel_to_commit()
+      .with_context(|_| format!("couldn't find tip of {}", name))?;
+    let upstream = match typ {
+      BranchType::Local => find_upstream(repo, &branch, commit.id())?,
+      BranchType::Remote => None,
+    };
+
+    summaries.push(BranchSummary {
+      name,
+      is_head: branch.is_head(),
+      is_remote: typ == BranchType::Remote,
+      commit,
+      upstream,
+    });
+  }
+
+  Ok(summaries)
+}
+
+/// Whether everything on a branch ending at `tip` is already in `target`.
+pub fn is_merged(repo: &Repository, tip: Oid, target: Oid) -> Result<bool, Error> {
+  if tip == target {
+    return Ok(true);
+  }
+
+  let merged = repo
+    .graph_descendant_of(target, tip)
+    .with_context(|_| "couldn't compare branches")?;
+  Ok(merged)
+}
+
+/// Prints one line per branch: its name, tip, how old the tip is, who wrote
+/// it, where it's tracking and the tip's subject.
+pub fn print_branches(repo: &Repository, branches: &[BranchSummary]) {
+  let rows: Vec<_> = branches
+    .iter()
+    .map(|branch| {
+      let author = branch.commit.author();
+      (
+        repo.get_short_id(branch.commit.id()),
+        relative_time(&branch.commit.time()),
+        author.name().unwrap_or("[???]").to_string(),
+      )
+    })
+    .collect();
+
+  let name_width = branches
+    .iter()
+    .map(|b| b.name.chars().count())
+    .max()
+    .unwrap_or(0);
+  let id_width = rows.iter().map(|(id, _, _)| id.len()).max().unwrap_or(0);
+  let age_width = rows.iter().map(|(_, age, _)| age.len()).max().unwrap_or(0);
+  let author_width = rows
+    .iter()
+    .map(|(_, _, author)| author.chars().count())
+    .max()
+    .unwrap_or(0);
+
+  for (branch, (id, age, author)) in branches.iter().zip(rows) {
+    let head_prefix = if branch.is_head { "*" } else { " " };
+    let name = format!("{:width$}", branch.name, width = name_width);
+    let name = if branch.is_head {
+      name.green()
+    } else if branch.is_remote {
+      name.red()
+    } else {
+      name.normal()
+    };
+
+    let upstream = match &branch.upstream {
+      Some(Upstream {
+        name,
+        distance: Some((ahead, behind)),
+      }) => format!("[{}: {}] ", name.cyan(), describe_distance(*ahead, *behind)),
+      Some(Upstream {
+        name,
+        distance: None,
+      }) => format!("[{}: {}] ", name.cyan(), "gone".red()),
+      None => String::new(),
+    };
+
+    println!(
+      "{} {} {} {} {} {}{}",
+      head_prefix,
+      name,
+      format!("{:width$}", id, width = id_width).bright_black(),
+      format!("{:width$}", age, width = age_width).yellow(),
+      format!("{:width$}", author, width = author_width).blue(),
+      upstream,
+      branch.commit.summary().unwrap_or("")
+    );
+  }
+}
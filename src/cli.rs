@@ -20,6 +20,10 @@ pub struct Global {
   /// Don't print information
   #[structopt(long = "quiet", short = "q")]
   pub quiet: bool,
+
+  /// Don't pipe long output into a pager
+  #[structopt(long = "no-pager")]
+  pub no_pager: bool,
 }
 
 // FIXME surely there's a way to propagate ColoredHelp to all members...?
@@ -71,6 +75,11 @@ pub enum Command {
   #[structopt(raw(setting = "structopt::clap::AppSettings::ColoredHelp"))]
   Me(Me),
 
+  /// List operations that can be undone
+  #[structopt(name = "oplog")]
+  #[structopt(raw(setting = "structopt::clap::AppSettings::ColoredHelp"))]
+  Oplog(Oplog),
+
   /// Dump contents of an object into a file
   #[structopt(name = "restore")]
   #[structopt(raw(setting = "structopt::clap::AppSettings::ColoredHelp"))]
@@ -91,11 +100,21 @@ pub enum Command {
   #[structopt(raw(setting = "structopt::clap::AppSettings::ColoredHelp"))]
   Status(Status),
 
+  /// Operate on submodules
+  #[structopt(name = "submodule")]
+  #[structopt(raw(setting = "structopt::clap::AppSettings::ColoredHelp"))]
+  Submodule(Submodule),
+
   /// Create a new tag
   #[structopt(name = "tag")]
   #[structopt(raw(setting = "structopt::clap::AppSettings::ColoredHelp"))]
   Tag(Tag),
 
+  /// Revert the changes made by a previous operation
+  #[structopt(name = "undo")]
+  #[structopt(raw(setting = "structopt::clap::AppSettings::ColoredHelp"))]
+  Undo(Undo),
+
   /// Unstage files from the index
   #[structopt(name = "unstage")]
   #[structopt(raw(setting = "structopt::clap::AppSettings::ColoredHelp"))]
@@ -105,6 +124,11 @@ pub enum Command {
   #[structopt(name = "where")]
   #[structopt(raw(setting = "structopt::clap::AppSettings::ColoredHelp"))]
   Where(Where),
+
+  /// Operate on linked worktrees
+  #[structopt(name = "worktree")]
+  #[structopt(raw(setting = "structopt::clap::AppSettings::ColoredHelp"))]
+  Worktree(Worktree),
 }
 
 #[derive(StructOpt, Debug)]
@@ -134,6 +158,16 @@ pub enum BranchCommand {
   #[structopt(name = "rm")]
   #[structopt(raw(setting = "structopt::clap::AppSettings::ColoredHelp"))]
   Rm(BranchRm),
+
+  /// Set the remote branch a branch tracks
+  #[structopt(name = "track")]
+  #[structopt(raw(setting = "structopt::clap::AppSettings::ColoredHelp"))]
+  Track(BranchTrack),
+
+  /// Stop a branch from tracking a remote branch
+  #[structopt(name = "untrack")]
+  #[structopt(raw(setting = "structopt::clap::AppSettings::ColoredHelp"))]
+  Untrack(BranchUntrack),
 }
 
 #[derive(StructOpt, Debug)]
@@ -147,6 +181,42 @@ pub struct BranchLs {
   /// Include remote branches in the list
   #[structopt(long = "remote", short = "r")]
   pub include_remote: bool,
+
+  /// How to order the branches: `name`, or `recent` for the newest last
+  /// commit first
+  #[structopt(
+    long = "sort",
+    short = "s",
+    default_value = "name",
+    raw(possible_values = "&[\"name\", \"recent\"]")
+  )]
+  pub sort: BranchSort,
+
+  /// Only list branches that are fully merged into this label
+  #[structopt(long = "merged", conflicts_with = "no_merged")]
+  pub merged: Option<String>,
+
+  /// Only list branches that aren't fully merged into this label
+  #[structopt(long = "no-merged")]
+  pub no_merged: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BranchSort {
+  Name,
+  Recent,
+}
+
+impl std::str::FromStr for BranchSort {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<BranchSort, String> {
+    match s {
+      "name" => Ok(BranchSort::Name),
+      "recent" => Ok(BranchSort::Recent),
+      _ => Err(format!("unknown sort order `{}`", s)),
+    }
+  }
 }
 
 #[derive(StructOpt, Debug)]
@@ -164,6 +234,10 @@ pub struct BranchNew {
   #[structopt(long = "ref", short = "r", default_value = "/HEAD")]
   pub ref_name: String,
 
+  /// Track the remote branch the new branch starts from
+  #[structopt(long = "track", short = "t")]
+  pub track: bool,
+
   /// Name of the new branch
   pub name: String,
 }
@@ -195,8 +269,27 @@ pub struct BranchRm {
   pub name: String,
 }
 
+#[derive(StructOpt, Debug)]
+pub struct BranchTrack {
+  /// Branch that should track the remote branch
+  pub name: String,
+
+  /// Remote branch to track, like `origin/master`
+  pub upstream: String,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct BranchUntrack {
+  /// Branch that should stop tracking its remote branch
+  pub name: String,
+}
+
 #[derive(StructOpt, Debug)]
 pub struct Clean {
+  /// List previous cleans instead of cleaning anything
+  #[structopt(long = "history")]
+  pub history: bool,
+
   /// Paths to clean
   pub paths: Vec<String>,
 }
@@ -216,6 +309,100 @@ pub struct Diff {
   /// Milk-style reference label to "new" tree-ish
   #[structopt(default_value = "/WORK")]
   pub new_tree: String,
+
+  /// Highlight the words that changed within modified lines
+  #[structopt(long = "word-diff", conflicts_with = "char_diff")]
+  pub word_diff: bool,
+
+  /// Highlight the characters that changed within modified lines
+  #[structopt(long = "char-diff")]
+  pub char_diff: bool,
+
+  /// Show a histogram of insertions and deletions per file instead of a patch
+  #[structopt(
+    long = "stat",
+    raw(conflicts_with_all = r#"&["numstat", "name_status"]"#)
+  )]
+  pub stat: bool,
+
+  /// Show tab-separated insertion and deletion counts per file
+  #[structopt(long = "numstat", conflicts_with = "name_status")]
+  pub numstat: bool,
+
+  /// Show only the status and name of each changed file
+  #[structopt(long = "name-status")]
+  pub name_status: bool,
+
+  /// Show the old and new contents next to each other
+  #[structopt(
+    long = "side-by-side",
+    short = "y",
+    raw(conflicts_with_all = r#"&["stat", "numstat", "name_status"]"#)
+  )]
+  pub side_by_side: bool,
+
+  /// Open each changed file in this external diff tool
+  #[structopt(long = "tool", short = "t")]
+  pub tool: Option<String>,
+
+  /// Open each changed file in the tool named by diff.tool
+  #[structopt(long = "external", short = "e", conflicts_with = "tool")]
+  pub external: bool,
+
+  /// Hand the tool two whole directories instead of one file at a time
+  ///
+  /// Uses diff.tool unless --tool is given.
+  #[structopt(long = "dir-diff", short = "d")]
+  pub dir_diff: bool,
+
+  /// Ignore whitespace when comparing lines
+  #[structopt(long = "ignore-all-space", short = "w")]
+  pub ignore_all_space: bool,
+
+  /// Ignore changes in the amount of whitespace
+  #[structopt(long = "ignore-space-change", short = "b")]
+  pub ignore_space_change: bool,
+
+  /// Ignore changes whose lines are all blank
+  #[structopt(long = "ignore-blank-lines")]
+  pub ignore_blank_lines: bool,
+
+  /// Number of context lines to show around each change
+  #[structopt(long = "unified", short = "U")]
+  pub context_lines: Option<u32>,
+
+  /// Show the whole function around each change as context
+  #[structopt(long = "function-context", short = "W")]
+  pub function_context: bool,
+
+  #[structopt(flatten)]
+  pub renames: Renames,
+
+  /// Only show changes to these paths
+  #[structopt(raw(last = "true"))]
+  pub paths: Vec<std::path::PathBuf>,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct Renames {
+  /// Don't pair up deleted and added files as renames
+  #[structopt(
+    long = "no-renames",
+    raw(conflicts_with_all = r#"&["find_copies", "threshold"]"#)
+  )]
+  pub no_renames: bool,
+
+  /// Also look for files that were copied from modified files
+  #[structopt(long = "find-copies", short = "C")]
+  pub find_copies: bool,
+
+  /// How similar two files must be, in percent, to count as a rename or copy
+  #[structopt(long = "find-renames", short = "M")]
+  pub threshold: Option<u16>,
+
+  /// Skip rename detection when there are more than this many candidates
+  #[structopt(long = "rename-limit")]
+  pub limit: Option<usize>,
 }
 
 #[derive(StructOpt, Debug)]
@@ -244,6 +431,28 @@ pub struct Ls {
   #[structopt(short = "ref", long = "r", default_value = "/HEAD")]
   pub ref_name: String,
 
+  /// List subtrees recursively
+  #[structopt(long = "recursive", short = "R")]
+  pub recursive: bool,
+
+  /// Show mode, object type, size and the commit that last changed each entry
+  #[structopt(long = "long", short = "l")]
+  pub long: bool,
+
+  /// Show the total size of every subtree
+  #[structopt(long = "du")]
+  pub disk_usage: bool,
+
+  /// Print the contents too when the path is a file
+  #[structopt(long = "cat", short = "c")]
+  pub cat: bool,
+
+  /// Mark entries with how they differ in the index and work tree
+  ///
+  /// Only works when listing the commit that HEAD points to.
+  #[structopt(long = "status", short = "s")]
+  pub status: bool,
+
   /// Subtree path to list
   #[structopt(default_value = "")]
   pub tree_path: std::path::PathBuf,
@@ -252,18 +461,59 @@ pub struct Ls {
 #[derive(StructOpt, Debug)]
 pub struct Me {}
 
+#[derive(StructOpt, Debug)]
+pub struct Oplog {}
+
 #[derive(StructOpt, Debug)]
 pub struct Restore {
-  /// Object ID to read contents from
-  pub object_name: String,
+  /// Restore every file saved by a previous clean, as numbered by
+  /// `milk clean --history`
+  #[structopt(long = "from-clean", conflicts_with = "object_name")]
+  pub from_clean: Option<usize>,
+
+  /// Also update the index to match the restored files
+  #[structopt(long = "index", short = "i")]
+  pub index: bool,
+
+  /// Overwrite files with local modifications
+  ///
+  /// Overwritten contents are saved the same way `milk clean` saves them.
+  #[structopt(long = "force", short = "f")]
+  pub force: bool,
+
+  /// Object ID to read contents from, or a `label:path` expression naming a
+  /// file or directory in a tree
+  #[structopt(required_unless = "from_clean")]
+  pub object_name: Option<String>,
 
   /// File path to write object
-  pub path: std::path::PathBuf,
+  ///
+  /// Defaults to the same path in the working tree for `label:path`
+  /// expressions.
+  pub path: Option<std::path::PathBuf>,
 }
 
 #[derive(StructOpt, Debug)]
 pub struct Show {
-  /// Milk-style reference label to object
+  /// Syntax highlighting theme for blobs
+  ///
+  /// Defaults to the `milk.theme` config value, or base16-ocean.dark.
+  #[structopt(long = "theme", short = "t")]
+  pub theme: Option<String>,
+
+  /// List the available syntax highlighting themes
+  #[structopt(long = "list-themes")]
+  pub list_themes: bool,
+
+  /// Print blobs as a hex dump with offsets and an ASCII gutter
+  #[structopt(long = "hex", short = "x", conflicts_with = "raw")]
+  pub hex: bool,
+
+  /// Print only the exact bytes of blobs, even binary ones, for piping
+  #[structopt(long = "raw", short = "r")]
+  pub raw: bool,
+
+  /// Milk-style reference label to object, or a `label:path` expression
   #[structopt(default_value = "/HEAD")]
   pub name: String,
 }
@@ -283,6 +533,88 @@ pub struct Status {
   /// Whether ignored files should be shown or not
   #[structopt(long = "show-ignored", short = "i")]
   pub show_ignored: bool,
+
+  #[structopt(flatten)]
+  pub renames: Renames,
+
+  /// Split files into staged, unstaged, untracked and conflicted sections
+  #[structopt(long = "group", short = "g", conflicts_with = "tree")]
+  pub group: bool,
+
+  /// Show changed files as a directory tree
+  #[structopt(long = "tree", short = "t", conflicts_with = "porcelain")]
+  pub tree: bool,
+
+  /// Print a stable, machine-readable format instead (see README)
+  #[structopt(long = "porcelain", conflicts_with = "group")]
+  pub porcelain: bool,
+
+  /// End porcelain records with NUL instead of newline, without quoting paths
+  #[structopt(short = "z", requires = "porcelain")]
+  pub nul: bool,
+
+  /// Keep the status on screen and redraw it whenever files change
+  #[structopt(long = "watch", short = "w", conflicts_with = "porcelain")]
+  pub watch: bool,
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(raw(setting = "structopt::clap::AppSettings::ColoredHelp"))]
+pub enum SubmoduleCommand {
+  /// Copy submodule URLs from .gitmodules into the repository config
+  #[structopt(name = "init")]
+  #[structopt(raw(setting = "structopt::clap::AppSettings::ColoredHelp"))]
+  Init(SubmoduleInit),
+
+  /// List all submodules
+  #[structopt(name = "ls")]
+  #[structopt(raw(setting = "structopt::clap::AppSettings::ColoredHelp"))]
+  Ls(SubmoduleLs),
+
+  /// Update submodule remote URLs to match .gitmodules
+  #[structopt(name = "sync")]
+  #[structopt(raw(setting = "structopt::clap::AppSettings::ColoredHelp"))]
+  Sync(SubmoduleSync),
+
+  /// Clone missing submodules and check out their recorded commits
+  #[structopt(name = "update")]
+  #[structopt(raw(setting = "structopt::clap::AppSettings::ColoredHelp"))]
+  Update(SubmoduleUpdate),
+}
+
+#[derive(StructOpt, Debug)]
+pub struct Submodule {
+  #[structopt(subcommand)]
+  pub command: SubmoduleCommand,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct SubmoduleInit {
+  /// Overwrite URLs that are already in the repository config
+  #[structopt(long = "force", short = "f")]
+  pub force: bool,
+
+  /// Submodules to initialize; all submodules if omitted
+  pub names: Vec<String>,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct SubmoduleLs {}
+
+#[derive(StructOpt, Debug)]
+pub struct SubmoduleSync {
+  /// Submodules to sync; all submodules if omitted
+  pub names: Vec<String>,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct SubmoduleUpdate {
+  /// Initialize submodules that haven't been initialized yet
+  #[structopt(long = "init", short = "i")]
+  pub init: bool,
+
+  /// Submodules to update; all submodules if omitted
+  pub names: Vec<String>,
 }
 
 #[derive(StructOpt, Debug)]
@@ -295,6 +627,16 @@ pub struct Tag {
   pub tag_name: String,
 }
 
+#[derive(StructOpt, Debug)]
+pub struct Undo {
+  /// Undo even if refs or the index have changed since the operation
+  #[structopt(long = "force", short = "f")]
+  pub force: bool,
+
+  /// Operation to undo, as numbered by `milk oplog`; defaults to the latest
+  pub id: Option<usize>,
+}
+
 #[derive(StructOpt, Debug)]
 pub struct Unstage {
   /// Paths to unstage
@@ -302,4 +644,100 @@ pub struct Unstage {
 }
 
 #[derive(StructOpt, Debug)]
-pub struct Where {}
+pub struct Where {
+  /// Print the main worktree instead of the current linked worktree
+  #[structopt(long = "main", short = "m")]
+  pub main: bool,
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(raw(setting = "structopt::clap::AppSettings::ColoredHelp"))]
+pub enum WorktreeCommand {
+  /// Lock a worktree to prevent it from being pruned
+  #[structopt(name = "lock")]
+  #[structopt(raw(setting = "structopt::clap::AppSettings::ColoredHelp"))]
+  Lock(WorktreeLock),
+
+  /// List all worktrees
+  #[structopt(name = "ls")]
+  #[structopt(raw(setting = "structopt::clap::AppSettings::ColoredHelp"))]
+  Ls(WorktreeLs),
+
+  /// Create a new linked worktree
+  #[structopt(name = "new")]
+  #[structopt(raw(setting = "structopt::clap::AppSettings::ColoredHelp"))]
+  New(WorktreeNew),
+
+  /// Remove information about worktrees that no longer exist
+  #[structopt(name = "prune")]
+  #[structopt(raw(setting = "structopt::clap::AppSettings::ColoredHelp"))]
+  Prune(WorktreePrune),
+
+  /// Remove a linked worktree and its working directory
+  #[structopt(name = "rm")]
+  #[structopt(raw(setting = "structopt::clap::AppSettings::ColoredHelp"))]
+  Rm(WorktreeRm),
+
+  /// Unlock a locked worktree
+  #[structopt(name = "unlock")]
+  #[structopt(raw(setting = "structopt::clap::AppSettings::ColoredHelp"))]
+  Unlock(WorktreeUnlock),
+}
+
+#[derive(StructOpt, Debug)]
+pub struct Worktree {
+  #[structopt(subcommand)]
+  pub command: WorktreeCommand,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct WorktreeLock {
+  /// Reason the worktree is locked
+  #[structopt(long = "reason", short = "m")]
+  pub reason: Option<String>,
+
+  /// Name of the worktree to lock
+  pub name: String,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct WorktreeLs {}
+
+#[derive(StructOpt, Debug)]
+pub struct WorktreeNew {
+  /// Existing local branch to check out in the new worktree
+  ///
+  /// If omitted, a new branch with the same name as the worktree is created
+  /// from HEAD.
+  #[structopt(long = "branch", short = "b")]
+  pub branch: Option<String>,
+
+  /// Lock the worktree immediately after creating it
+  #[structopt(long = "lock", short = "l")]
+  pub lock: bool,
+
+  /// Name of the new worktree
+  pub name: String,
+
+  /// Directory to create the worktree in
+  pub path: std::path::PathBuf,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct WorktreePrune {}
+
+#[derive(StructOpt, Debug)]
+pub struct WorktreeRm {
+  /// Remove the worktree even if it is locked or has local modifications
+  #[structopt(long = "force", short = "f")]
+  pub force: bool,
+
+  /// Name of the worktree to remove
+  pub name: String,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct WorktreeUnlock {
+  /// Name of the worktree to unlock
+  pub name: String,
+}
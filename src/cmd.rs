@@ -1,29 +1,89 @@
+use super::binary;
+use super::branch;
 use super::cli;
 use super::cli::BranchCommand;
 use super::cli::Command;
+use super::cli::SubmoduleCommand;
+use super::cli::WorktreeCommand;
+use super::diff;
+use super::diff::print_patch;
+use super::diff::Granularity;
+use super::difftool;
 use super::editor;
-use super::find_subtree;
-use super::get_status_string;
+use super::file_side;
+use super::get_file_mode;
+use super::highlight;
+use super::journal;
+use super::journal::CleanRecord;
+use super::journal::CleanedFile;
+use super::journal::PendingOperation;
+use super::ls;
+use super::pager;
+use super::split_label_path;
+use super::status;
+use super::DiffSettings;
 use super::DiffTarget;
 use super::MilkRepo;
+use super::RenameDetection;
+use super::RepoPath;
 use colored::*;
 use exitcode;
+use failure::format_err;
 use failure::Error;
 use failure::ResultExt;
 use git2::build::CheckoutBuilder;
+use git2::Blob;
+use git2::Branch;
 use git2::BranchType;
+use git2::Config;
 use git2::ObjectType;
+use git2::Oid;
 use git2::Repository;
 use git2::RepositoryInitOptions;
 use git2::ResetType;
+use git2::Status;
 use git2::StatusOptions;
+use git2::Submodule;
+use git2::TreeWalkMode;
+use git2::TreeWalkResult;
+use git2::Worktree;
+use git2::WorktreeAddOptions;
+use git2::WorktreeLockStatus;
+use git2::WorktreePruneOptions;
+use std::env;
+use std::fs;
 use std::fs::OpenOptions;
+use std::io;
 use std::io::prelude::*;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::exit;
 
 pub fn main(args: cli::Root) -> Result<(), Error> {
+  let pager_name = match args.command {
+    // external tools take over the terminal themselves
+    Command::Diff(ref diff_args)
+      if diff_args.tool.is_some() || diff_args.external || diff_args.dir_diff =>
+    {
+      None
+    }
+    Command::Diff(_) => Some("diff"),
+    Command::Show(_) => Some("show"),
+    _ => None,
+  };
+
+  let _pager = match pager_name {
+    Some(name) if !args.globals.no_pager => {
+      let config = match Repository::discover(&args.globals.repo_path) {
+        Ok(repo) => repo.config(),
+        Err(_) => Config::open_default(),
+      }
+      .with_context(|_| "couldn't open config")?;
+      pager::start(&config, name)?
+    }
+    _ => None,
+  };
+
   match args.command {
     Command::Branch(cmd_args) => match cmd_args.command {
       BranchCommand::Ls(subcmd_args) => branch_ls(args.globals, subcmd_args),
@@ -31,6 +91,8 @@ pub fn main(args: cli::Root) -> Result<(), Error> {
       BranchCommand::New(subcmd_args) => branch_new(args.globals, subcmd_args),
       BranchCommand::Rename(subcmd_args) => branch_rename(args.globals, subcmd_args),
       BranchCommand::Rm(subcmd_args) => branch_rm(args.globals, subcmd_args),
+      BranchCommand::Track(subcmd_args) => branch_track(args.globals, subcmd_args),
+      BranchCommand::Untrack(subcmd_args) => branch_untrack(args.globals, subcmd_args),
     },
     Command::Clean(cmd_args) => clean(args.globals, cmd_args),
     Command::Commit(cmd_args) => commit(args.globals, cmd_args),
@@ -40,13 +102,29 @@ pub fn main(args: cli::Root) -> Result<(), Error> {
     Command::Init(cmd_args) => init(args.globals, cmd_args),
     Command::Ls(cmd_args) => ls(args.globals, cmd_args),
     Command::Me(cmd_args) => me(args.globals, cmd_args),
+    Command::Oplog(cmd_args) => oplog(args.globals, cmd_args),
     Command::Restore(cmd_args) => restore(args.globals, cmd_args),
     Command::Show(cmd_args) => show(args.globals, cmd_args),
     Command::Stage(cmd_args) => stage(args.globals, cmd_args),
     Command::Status(cmd_args) => status(args.globals, cmd_args),
+    Command::Submodule(cmd_args) => match cmd_args.command {
+      SubmoduleCommand::Init(subcmd_args) => submodule_init(args.globals, subcmd_args),
+      SubmoduleCommand::Ls(subcmd_args) => submodule_ls(args.globals, subcmd_args),
+      SubmoduleCommand::Sync(subcmd_args) => submodule_sync(args.globals, subcmd_args),
+      SubmoduleCommand::Update(subcmd_args) => submodule_update(args.globals, subcmd_args),
+    },
     Command::Tag(cmd_args) => tag(args.globals, cmd_args),
+    Command::Undo(cmd_args) => undo(args.globals, cmd_args),
     Command::Unstage(cmd_args) => unstage(args.globals, cmd_args),
     Command::Where(cmd_args) => where_(args.globals, cmd_args),
+    Command::Worktree(cmd_args) => match cmd_args.command {
+      WorktreeCommand::Lock(subcmd_args) => worktree_lock(args.globals, subcmd_args),
+      WorktreeCommand::Ls(subcmd_args) => worktree_ls(args.globals, subcmd_args),
+      WorktreeCommand::New(subcmd_args) => worktree_new(args.globals, subcmd_args),
+      WorktreeCommand::Prune(subcmd_args) => worktree_prune(args.globals, subcmd_args),
+      WorktreeCommand::Rm(subcmd_args) => worktree_rm(args.globals, subcmd_args),
+      WorktreeCommand::Unlock(subcmd_args) => worktree_unlock(args.globals, subcmd_args),
+    },
   }?;
 
   Ok(())
@@ -56,23 +134,45 @@ pub fn branch_ls(globals: cli::Global, args: cli::BranchLs) -> Result<(), Error>
   let repo =
     Repository::discover(globals.repo_path).with_context(|_| "couldn't open repository")?;
 
-  let branches = repo
-    .branches(None)
-    .with_context(|_| "couldn't iterate branches")?;
+  let mut branches = branch::collect_branches(&repo, args.include_remote)?;
 
-  for branch in branches {
-    let (branch, typ) = branch.with_context(|_| "couldn't identify branch")?;
-    let name = branch
-      .name()
-      .with_context(|_| "couldn't identify branch name")?
-      .unwrap_or("[branch name is invalid utf8]");
-    let head_prefix = if branch.is_head() { "*" } else { " " };
-    match (typ, args.include_remote) {
-      (BranchType::Remote, false) => (),
-      _ => println!("{} {}", head_prefix, name),
+  let merge_filter = match (&args.merged, &args.no_merged) {
+    (Some(label), _) => Some((label, true)),
+    (_, Some(label)) => Some((label, false)),
+    _ => None,
+  };
+
+  if let Some((label, want_merged)) = merge_filter {
+    let target = repo
+      .find_from_name(label)
+      .and_then(|object| {
+        object
+          .peel_to_commit()
+          .with_context(|_| "label didn't point to a commit")
+          .map_err(Error::from)
+      })
+      .with_context(|_| format!("couldn't look up {}", label))?;
+
+    let mut kept = Vec::new();
+    for summary in branches {
+      if branch::is_merged(&repo, summary.commit.id(), target.id())? == want_merged {
+        kept.push(summary);
+      }
+    }
+    branches = kept;
+  }
+
+  match args.sort {
+    cli::BranchSort::Name => {
+      branches.sort_by(|a, b| (a.is_remote, &a.name).cmp(&(b.is_remote, &b.name)))
+    }
+    cli::BranchSort::Recent => {
+      branches.sort_by_key(|summary| std::cmp::Reverse(summary.commit.time()))
     }
   }
 
+  branch::print_branches(&repo, &branches);
+
   Ok(())
 }
 
@@ -91,10 +191,15 @@ pub fn branch_mv(globals: cli::Global, args: cli::BranchMv) -> Result<(), Error>
   if let Some(ObjectType::Commit) = dest_object.kind() {
     let commit = dest_object.into_commit().unwrap();
 
+    let mut op = PendingOperation::new(&format!("branch mv {} {}", args.src_name, args.dest_ref));
+    op.track_ref(&repo, &format!("refs/heads/{}", args.src_name));
+
     repo
       .branch(&args.src_name, &commit, true)
       .with_context(|_| "couldn't move branch")?;
 
+    op.record(&repo)?;
+
     println!("Moved branch");
     println!("{}", repo.highlight_named_oid(&args.src_name, commit.id()));
     repo.print_commit(&commit);
@@ -108,20 +213,58 @@ pub fn branch_mv(globals: cli::Global, args: cli::BranchMv) -> Result<(), Error>
 pub fn branch_new(globals: cli::Global, args: cli::BranchNew) -> Result<(), Error> {
   let repo =
     Repository::discover(globals.repo_path).with_context(|_| "couldn't open repository")?;
-  let object = repo
-    .find_from_name(&args.ref_name)
-    .with_context(|_| "couldn't look up ref")?;
+
+  // a tracked start point is a remote branch, either as origin/master or as
+  // the full label /refs/remotes/origin/master
+  let (object, upstream) = if args.track {
+    let name = args.ref_name.trim_start_matches("/refs/remotes/");
+    let remote_branch = repo
+      .find_branch(name, BranchType::Remote)
+      .with_context(|_| "--track needs a remote branch to start from")?;
+    let upstream = remote_branch
+      .name()
+      .with_context(|_| "couldn't identify remote branch name")?
+      .ok_or_else(|| failure::err_msg("remote branch name is invalid utf8"))?
+      .to_string();
+    let object = remote_branch
+      .get()
+      .peel(ObjectType::Commit)
+      .with_context(|_| "couldn't find remote branch tip")?;
+    (object, Some(upstream))
+  } else {
+    let object = repo
+      .find_from_name(&args.ref_name)
+      .with_context(|_| "couldn't look up ref")?;
+    (object, None)
+  };
 
   if let Some(ObjectType::Commit) = object.kind() {
     let commit = object.into_commit().unwrap();
 
-    repo
+    let mut op = PendingOperation::new(&format!("branch new {}", args.name));
+    op.track_ref(&repo, &format!("refs/heads/{}", args.name));
+
+    let mut branch = repo
       .branch(&args.name, &commit, false)
       .with_context(|_| "couldn't create branch")?;
 
+    // the upstream lives in the branch's config, which goes away along with
+    // the branch if this is undone
+    if let Some(upstream) = &upstream {
+      branch
+        .set_upstream(Some(upstream))
+        .with_context(|_| "couldn't set upstream")?;
+    }
+
+    op.record(&repo)?;
+
     println!("Created branch");
     println!("{}", repo.highlight_named_oid(&args.name, commit.id()));
 
+    if let Some(upstream) = upstream {
+      println!("{} {}", "tracking".cyan(), upstream);
+    }
+
     repo.print_commit(&commit);
   } else {
     Err(failure::err_msg("object was not a commit"))?;
@@ -130,6 +273,50 @@ pub fn branch_new(globals: cli::Global, args: cli::BranchNew) -> Result<(), Erro
   Ok(())
 }
 
+pub fn branch_track(globals: cli::Global, args: cli::BranchTrack) -> Result<(), Error> {
+  let repo =
+    Repository::discover(globals.repo_path).with_context(|_| "couldn't open repository")?;
+
+  let mut branch = repo
+    .find_branch(&args.name, BranchType::Local)
+    .with_context(|_| "couldn't find branch")?;
+
+  repo
+    .find_branch(&args.upstream, BranchType::Remote)
+    .with_context(|_| "couldn't find remote branch")?;
+
+  branch
+    .set_upstream(Some(&args.upstream))
+    .with_context(|_| "couldn't set upstream")?;
+
+  println!("Tracking remote branch");
+  if let Some(tracking) = branch::describe_tracking(&repo, &branch)? {
+    println!("{} -> {}", args.name.green(), tracking);
+  }
+
+  Ok(())
+}
+
+pub fn branch_untrack(globals: cli::Global, args: cli::BranchUntrack) -> Result<(), Error> {
+  let repo =
+    Repository::discover(globals.repo_path).with_context(|_| "couldn't open repository")?;
+
+  let mut branch = repo
+    .find_branch(&args.name, BranchType::Local)
+    .with_context(|_| "couldn't find branch")?;
+
+  let tracking = branch::describe_tracking(&repo, &branch)?
+    .ok_or_else(|| format_err!("{} isn't tracking anything", args.name))?;
+
+  branch
+    .set_upstream(None)
+    .with_context(|_| "couldn't unset upstream")?;
+
+  println!("Stopped tracking {}", tracking);
+
+  Ok(())
+}
+
 pub fn branch_rename(globals: cli::Global, args: cli::BranchRename) -> Result<(), Error> {
   let repo =
     Repository::discover(globals.repo_path).with_context(|_| "couldn't open repository")?;
@@ -140,14 +327,28 @@ pub fn branch_rename(globals: cli::Global, args: cli::BranchRename) -> Result<()
     BranchType::Local
   };
 
+  let ref_prefix = match typ {
+    BranchType::Local => "refs/heads",
+    BranchType::Remote => "refs/remotes",
+  };
+
   let mut branch = repo
     .find_branch(&args.src_name, typ)
     .with_context(|_| "couldn't find branch")?;
 
+  let mut op = PendingOperation::new(&format!(
+    "branch rename {} {}",
+    args.src_name, args.dest_name
+  ));
+  op.track_ref(&repo, &format!("{}/{}", ref_prefix, args.src_name));
+  op.track_ref(&repo, &format!("{}/{}", ref_prefix, args.dest_name));
+
   branch
     .rename(&args.dest_name, args.force)
     .with_context(|_| "couldn't rename branch")?;
 
+  op.record(&repo)?;
+
   let target = branch
     .get()
     .target()
@@ -178,8 +379,15 @@ pub fn branch_rm(globals: cli::Global, args: cli::BranchRm) -> Result<(), Error>
     .find_branch(&args.name, typ)
     .with_context(|_| "couldn't find branch")?;
 
+  let mut op = PendingOperation::new(&format!("branch rm {}", args.name));
+  if let Some(ref_name) = branch.get().name() {
+    op.track_ref(&repo, ref_name);
+  }
+
   branch.delete().with_context(|_| "couldn't delete branch")?;
 
+  op.record(&repo)?;
+
   let target = branch
     .get()
     .target()
@@ -199,18 +407,32 @@ pub fn clean(globals: cli::Global, args: cli::Clean) -> Result<(), Error> {
   let repo =
     Repository::discover(globals.repo_path).with_context(|_| "couldn't open repository")?;
 
+  if args.history {
+    return clean_history(&repo);
+  }
+
+  let workdir = repo
+    .workdir()
+    .ok_or_else(|| failure::err_msg("repository is bare"))?;
+
+  // paths are given relative to the current directory, but checkout and the
+  // journal want them relative to the work tree
+  let mut paths = Vec::new();
+  for path in &args.paths {
+    paths.push(to_pathspec(&repo, Path::new(path))?);
+  }
+
   let mut checkout = CheckoutBuilder::new();
   checkout.force();
 
-  for path in &args.paths {
+  for path in &paths {
     checkout.path(path);
   }
 
-  if !args.paths.is_empty() {
-    for path in &args.paths {
-      let oid = repo.write_blob(Path::new(path))?;
-      println!("{}", repo.highlight_named_oid(path, oid));
-    }
+  let mut dirty_paths = Vec::new();
+
+  if !paths.is_empty() {
+    dirty_paths.extend(paths);
   } else {
     let mut status_opts = StatusOptions::new();
     status_opts.include_untracked(false);
@@ -224,17 +446,65 @@ pub fn clean(globals: cli::Global, args: cli::Clean) -> Result<(), Error> {
       if let Some(path) = entry.path() {
         let status = entry.status();
         if status.is_wt_modified() || status.is_index_modified() {
-          let oid = repo.write_blob(Path::new(path))?;
-          println!("{}", repo.highlight_named_oid(path, oid));
+          dirty_paths.push(path.to_string());
         }
       }
     }
   }
 
+  let mut op = PendingOperation::new(&format!("clean {}", args.paths.join(" ")));
+  op.track_index(&repo);
+
+  let mut cleaned_files = Vec::new();
+
+  for path in dirty_paths {
+    let full_path = workdir.join(&path);
+    let oid = repo.write_blob(&full_path)?;
+    let mode = get_file_mode(&full_path)?;
+    println!("{}", repo.highlight_named_oid(&path, oid));
+    cleaned_files.push(CleanedFile { path, oid, mode });
+  }
+
   repo
     .checkout_head(Some(&mut checkout))
     .with_context(|_| "couldn't checkout")?;
 
+  // the blobs are already written, but the clean only counts once the files
+  // are actually gone
+  if !cleaned_files.is_empty() {
+    let id = journal::record_clean(&repo, &cleaned_files)?;
+    op.set_clean(id);
+    if !globals.quiet {
+      println!(
+        "Saved as clean {}; restore with `milk restore --from-clean {}`",
+        id.to_string().cyan(),
+        id
+      );
+    }
+  }
+
+  op.record(&repo)?;
+
+  Ok(())
+}
+
+fn clean_history(repo: &Repository) -> Result<(), Error> {
+  for record in journal::read_cleans(repo)?.iter().rev() {
+    println!(
+      "clean {} {}",
+      record.id.to_string().cyan(),
+      record.time.to_string().bright_blue()
+    );
+
+    for file in &record.files {
+      println!(
+        "  {:o} {}",
+        file.mode,
+        repo.highlight_named_oid(&file.path, file.oid)
+      );
+    }
+  }
+
   Ok(())
 }
 
@@ -271,10 +541,15 @@ pub fn commit(globals: cli::Global, _args: cli::Commit) -> Result<(), Error> {
     exit(exitcode::DATAERR);
   }
 
+  let mut op = PendingOperation::new("commit");
+  op.track_ref(&repo, head.name().unwrap_or("HEAD"));
+
   let new_commit_id = repo
     .commit(Some("HEAD"), &sig, &sig, &message, &tree, &parents)
     .with_context(|_| "couldn't write commit")?;
 
+  op.record(&repo)?;
+
   let new_commit = repo
     .find_commit(new_commit_id)
     .with_context(|_| "couldn't find commit")?;
@@ -286,6 +561,61 @@ pub fn commit(globals: cli::Global, _args: cli::Commit) -> Result<(), Error> {
   Ok(())
 }
 
+// pathspecs are given relative to the current directory, but libgit2 wants
+// them relative to the work tree
+fn to_pathspec(repo: &Repository, path: &Path) -> Result<String, Error> {
+  match repo.canonicalize_path(path)? {
+    RepoPath::Path(path) => Ok(path.to_string_lossy().into_owned()),
+    RepoPath::NotRepo => Err(format_err!("{} is outside the repository", path.display())),
+
+    // deleted files and glob patterns don't exist on disk, so resolve them
+    // against the current directory without touching the filesystem
+    RepoPath::NotFound => {
+      let workdir = repo
+        .workdir()
+        .ok_or_else(|| failure::err_msg("repository is bare"))?
+        .canonicalize()
+        .with_context(|_| "couldn't resolve work tree")?;
+      let cwd = env::current_dir()
+        .and_then(|cwd| cwd.canonicalize())
+        .with_context(|_| "couldn't resolve current directory")?;
+      let prefix = cwd
+        .strip_prefix(&workdir)
+        .map_err(|_| failure::err_msg("current directory is outside the repository"))?;
+
+      Ok(prefix.join(path).to_string_lossy().into_owned())
+    }
+  }
+}
+
+fn rename_detection(repo: &Repository, args: &cli::Renames) -> Result<RenameDetection, Error> {
+  let config = repo.config().with_context(|_| "couldn't open config")?;
+  let mut renames = RenameDetection::from_config(&config);
+
+  if args.no_renames {
+    renames.renames = false;
+    renames.copies = false;
+  }
+
+  if args.find_copies {
+    renames.copies = true;
+  }
+
+  if let Some(threshold) = args.threshold {
+    if threshold > 100 {
+      return Err(failure::err_msg("rename threshold must be a percentage"));
+    }
+    renames.renames = true;
+    renames.threshold = Some(threshold);
+  }
+
+  if args.limit.is_some() {
+    renames.limit = args.limit;
+  }
+
+  Ok(renames)
+}
+
 pub fn diff(globals: cli::Global, args: cli::Diff) -> Result<(), Error> {
   let repo =
     Repository::discover(globals.repo_path).with_context(|_| "couldn't open repository")?;
@@ -293,25 +623,65 @@ pub fn diff(globals: cli::Global, args: cli::Diff) -> Result<(), Error> {
   let old_target = DiffTarget::from_str(&args.old_tree);
   let new_target = DiffTarget::from_str(&args.new_tree);
 
+  let mut pathspecs = Vec::new();
+  for path in &args.paths {
+    pathspecs.push(to_pathspec(&repo, path)?);
+  }
+
+  let settings = DiffSettings {
+    pathspecs,
+    ignore_whitespace: args.ignore_all_space,
+    ignore_whitespace_change: args.ignore_space_change,
+    ignore_blank_lines: args.ignore_blank_lines,
+    context_lines: args.context_lines,
+    renames: rename_detection(&repo, &args.renames)?,
+  };
+
   let diff = repo
-    .make_diff(old_target, new_target)
+    .make_diff(old_target, new_target, &settings)
     .with_context(|_| "failed to diff")?;
 
-  // this API is literally insane
-  // example code yanked from here:
-  //   https://github.com/rust-lang/git2-rs/blob/master/examples/diff.rs#L153-L179
-  diff
-    .print(git2::DiffFormat::Patch, |_delta, _hunk, line| {
-      let display = std::str::from_utf8(line.content()).unwrap();
-      match line.origin() {
-        '+' => print!("{}{}", "+".green(), display.green()),
-        '-' => print!("{}{}", "-".red(), display.red()),
-        ' ' => print!(" {}", display.white()),
-        _ => print!("{}", display.cyan()),
-      }
-      true
-    })
-    .with_context(|_| "failed to print diff")?;
+  let granularity = if args.word_diff {
+    Some(Granularity::Word)
+  } else if args.char_diff {
+    Some(Granularity::Char)
+  } else {
+    None
+  };
+
+  if args.tool.is_some() || args.external || args.dir_diff {
+    let config = repo.config().with_context(|_| "couldn't open config")?;
+    let tool = match args.tool {
+      Some(tool) => tool,
+      None => difftool::configured_tool(&config)?,
+    };
+    let command = difftool::tool_command(&config, &tool);
+
+    match (file_side(&repo, old_target)?, file_side(&repo, new_target)?) {
+      (Some(old), Some(new)) => difftool::run_files(&old, &new, &command)?,
+      _ => difftool::run(&repo, &diff, &command, args.dir_diff)?,
+    }
+  } else if args.stat {
+    diff::print_stat(&diff)?;
+  } else if args.numstat {
+    diff::print_numstat(&diff)?;
+  } else if args.name_status {
+    diff::print_name_status(&diff)?;
+  } else {
+    let diff = if args.function_context {
+      repo
+        .make_function_diff(old_target, new_target, &settings)
+        .with_context(|_| "failed to diff")?
+    } else {
+      diff
+    };
+
+    if args.side_by_side {
+      diff::print_side_by_side(&diff, granularity.unwrap_or(Granularity::Word))?;
+    } else {
+      print_patch(&diff, granularity)?;
+    }
+  }
 
   Ok(())
 }
@@ -319,6 +689,8 @@ pub fn diff(globals: cli::Global, args: cli::Diff) -> Result<(), Error> {
 pub fn head(globals: cli::Global, _args: cli::Head) -> Result<(), Error> {
   let repo =
     Repository::discover(globals.repo_path).with_context(|_| "couldn't open repository")?;
+  status::print_state_banner(&repo);
+
   let head = repo.head().with_context(|_| "couldn't locate HEAD")?;
   let commit = head
     .peel_to_commit()
@@ -380,9 +752,22 @@ pub fn ls(globals: cli::Global, args: cli::Ls) -> Result<(), Error> {
     exit(exitcode::USAGE);
   }
 
+  if args.status {
+    let head = repo
+      .head()
+      .and_then(|head| head.peel_to_commit())
+      .with_context(|_| "couldn't find HEAD")?;
+    if head.id() != commit.id() {
+      eprintln!("--status only works when listing HEAD");
+      exit(exitcode::USAGE);
+    }
+  }
+
   let mut tree = commit.tree().with_context(|_| "couldn't find tree")?;
+  let mut walked = PathBuf::new();
+  let frags: Vec<_> = args.tree_path.iter().collect();
 
-  for frag in args.tree_path.iter() {
+  for (i, frag) in frags.iter().enumerate() {
     let frag_name = match frag.to_str() {
       Some(x) => x,
       None => {
@@ -390,28 +775,59 @@ pub fn ls(globals: cli::Global, args: cli::Ls) -> Result<(), Error> {
         exit(exitcode::USAGE);
       }
     };
+    walked.push(frag_name);
+
+    let entry = match tree.get_name(frag_name) {
+      Some(entry) => entry.to_owned(),
+      None => {
+        eprintln!("`{}` not found", walked.display());
+        exit(exitcode::USAGE);
+      }
+    };
 
-    match find_subtree(&tree, &frag_name) {
-      Some(next_tree_id) => {
+    match entry.kind() {
+      Some(ObjectType::Tree) => {
         println!(
           "{}/ {}",
           frag_name.cyan(),
-          repo.get_short_id(next_tree_id).bright_black()
+          repo.get_short_id(entry.id()).bright_black()
         );
         tree = repo
-          .find_tree(next_tree_id)
+          .find_tree(entry.id())
           .with_context(|_| "couldn't find tree")?;
       }
-      None => {
-        eprintln!("Subtree `{}` did not exist", frag_name);
+      Some(ObjectType::Blob) if i + 1 == frags.len() => {
+        let blob = repo
+          .find_blob(entry.id())
+          .with_context(|_| "couldn't find blob")?;
+        let status = if args.status {
+          let statuses = ls::work_tree_statuses(&repo)?;
+          Some(statuses.get(&walked).cloned().unwrap_or(Status::CURRENT))
+        } else {
+          None
+        };
+        ls::print_file(&repo, &commit, &entry, &blob, &walked, status)?;
+
+        if args.cat && !blob.is_binary() {
+          println!();
+          print_blob_contents(&repo, &blob, &walked, None)?;
+        }
+        return Ok(());
+      }
+      _ => {
+        eprintln!("`{}` is not a directory", walked.display());
         exit(exitcode::USAGE);
       }
-    };
+    }
   }
 
-  repo.print_tree(&tree);
-
-  Ok(())
+  let options = ls::ListOptions {
+    recursive: args.recursive,
+    long: args.long,
+    disk_usage: args.disk_usage,
+    status: args.status,
+  };
+  ls::print_listing(&repo, &commit, &tree, &args.tree_path, &options)
 }
 
 pub fn me(globals: cli::Global, _args: cli::Me) -> Result<(), Error> {
@@ -441,11 +857,79 @@ pub fn me(globals: cli::Global, _args: cli::Me) -> Result<(), Error> {
   Ok(())
 }
 
+pub fn oplog(globals: cli::Global, _args: cli::Oplog) -> Result<(), Error> {
+  let repo =
+    Repository::discover(globals.repo_path).with_context(|_| "couldn't open repository")?;
+
+  for operation in journal::read_operations(&repo)?.iter().rev() {
+    println!(
+      "op {} {} {}",
+      operation.id.to_string().cyan(),
+      operation.time.to_string().bright_blue(),
+      operation.description
+    );
+
+    for change in &operation.refs {
+      if change.old != change.new {
+        println!(
+          "  {} {} -> {}",
+          change.name,
+          describe_optional_oid(&repo, change.old),
+          describe_optional_oid(&repo, change.new)
+        );
+      }
+    }
+
+    if let Some((old_tree, new_tree)) = operation.index {
+      if old_tree != new_tree {
+        println!(
+          "  index {} -> {}",
+          repo.get_short_id(old_tree).bright_black(),
+          repo.get_short_id(new_tree).bright_black()
+        );
+      }
+    }
+
+    if let Some(clean) = operation.clean {
+      println!("  clean {}", clean.to_string().cyan());
+    }
+  }
+
+  Ok(())
+}
+
 pub fn restore(globals: cli::Global, args: cli::Restore) -> Result<(), Error> {
   let repo =
     Repository::discover(globals.repo_path).with_context(|_| "couldn't open repository")?;
+
+  let object_name = match (args.from_clean, args.object_name) {
+    (Some(id), _) => return restore_from_clean(&repo, id, args.force),
+    (None, Some(object_name)) => object_name,
+    (None, None) => {
+      eprintln!("Either an object or --from-clean is required");
+      exit(exitcode::USAGE);
+    }
+  };
+
+  if let Some((label, src_path)) = split_label_path(&object_name) {
+    let opts = TreeRestoreOptions {
+      dest: args.path,
+      update_index: args.index,
+      force: args.force,
+    };
+    return restore_from_tree(&repo, label, src_path, opts);
+  }
+
+  let path = match args.path {
+    Some(path) => path,
+    None => {
+      eprintln!("A destination path is required when restoring a blob by id");
+      exit(exitcode::USAGE);
+    }
+  };
+
   let object = repo
-    .find_from_name(&args.object_name)
+    .find_from_name(&object_name)
     .with_context(|_| "couldn't look up object")?;
 
   let blob = match object.into_blob() {
@@ -461,7 +945,7 @@ pub fn restore(globals: cli::Global, args: cli::Restore) -> Result<(), Error> {
     .write(true)
     .truncate(true)
     .create(true)
-    .open(args.path)
+    .open(path)
     .with_context(|_| "couldn't open file for writing")?;
 
   file
@@ -471,7 +955,265 @@ pub fn restore(globals: cli::Global, args: cli::Restore) -> Result<(), Error> {
   Ok(())
 }
 
+struct TreeRestoreOptions {
+  dest: Option<PathBuf>,
+  update_index: bool,
+  force: bool,
+}
+
+fn restore_from_tree(
+  repo: &Repository,
+  label: &str,
+  src_path: &Path,
+  opts: TreeRestoreOptions,
+) -> Result<(), Error> {
+  let workdir = repo
+    .workdir()
+    .ok_or_else(|| failure::err_msg("repository is bare"))?;
+
+  let object = repo.find_from_label_path(label, src_path)?;
+
+  // explicit destinations are relative to where the user is standing, just
+  // like the blob form of restore; otherwise files go back where they were
+  let dest_root = match &opts.dest {
+    Some(dest) => env::current_dir()
+      .with_context(|_| "couldn't find current directory")?
+      .join(dest),
+    None => workdir.join(src_path),
+  };
+
+  // (destination, blob, mode) for every file that will be written
+  let mut targets = Vec::new();
+
+  match object.kind() {
+    Some(ObjectType::Blob) => {
+      let mode = repo
+        .name_to_tree(label)?
+        .get_path(src_path)
+        .with_context(|_| "couldn't find tree entry")?
+        .filemode();
+      targets.push((dest_root, object.id(), mode));
+    }
+    Some(ObjectType::Tree) => {
+      let tree = object.as_tree().unwrap();
+      tree
+        .walk(TreeWalkMode::PreOrder, |root, entry| {
+          let name = entry.name().unwrap_or("[invalid utf-8]");
+          match entry.kind() {
+            Some(ObjectType::Blob) => {
+              targets.push((
+                dest_root.join(root).join(name),
+                entry.id(),
+                entry.filemode(),
+              ));
+            }
+            Some(ObjectType::Commit) => {
+              eprintln!("{}: skipping submodule @{}{}", "warning".red(), root, name);
+            }
+            _ => (),
+          }
+          TreeWalkResult::Ok
+        })
+        .with_context(|_| "couldn't walk tree")?;
+    }
+    _ => {
+      return Err(failure::err_msg("path didn't point to a file or directory"));
+    }
+  }
+
+  let mut op = PendingOperation::new(&format!("restore {}:{}", label, src_path.display()));
+  if opts.update_index {
+    op.track_index(repo);
+  }
+
+  let dests: Vec<_> = targets.iter().map(|(dest, _, _)| dest.as_path()).collect();
+  let dirty_files = find_dirty_files(repo, workdir, &dests, opts.update_index);
+  save_dirty_files(repo, workdir, dirty_files, opts.force, "restore", &mut op)?;
+
+  let mut index = repo.index().with_context(|_| "couldn't open index")?;
+
+  for (dest, oid, mode) in &targets {
+    repo
+      .restore_blob(*oid, *mode, dest)
+      .with_context(|_| format!("couldn't restore {}", dest.display()))?;
+
+    let shown_path = dest.strip_prefix(workdir).unwrap_or(dest);
+    println!(
+      "{}",
+      repo.highlight_named_oid(&shown_path.to_string_lossy(), *oid)
+    );
+
+    if opts.update_index {
+      let rel_path = dest
+        .strip_prefix(workdir)
+        .map_err(|_| failure::err_msg("can't stage files outside of the working tree"))?;
+      index
+        .add_path(rel_path)
+        .with_context(|_| "couldn't add path to index")?;
+    }
+  }
+
+  if opts.update_index {
+    index.write().with_context(|_| "couldn't write index")?;
+  }
+
+  op.record(repo)?;
+
+  Ok(())
+}
+
+// files among `dests` that git couldn't give back if they were overwritten:
+// untracked, ignored or modified ones, plus staged changes if the index is
+// about to be touched as well
+fn find_dirty_files(
+  repo: &Repository,
+  workdir: &Path,
+  dests: &[&Path],
+  include_index: bool,
+) -> Vec<PathBuf> {
+  let mut dirty_files = Vec::new();
+  for dest in dests {
+    if fs::symlink_metadata(dest).is_err() {
+      continue;
+    }
+
+    let is_dirty = match dest.strip_prefix(workdir) {
+      Ok(rel_path) => {
+        let status = repo.status_file(rel_path).unwrap_or(Status::WT_NEW);
+        let mut dirty_flags =
+          Status::WT_NEW | Status::WT_MODIFIED | Status::WT_TYPECHANGE | Status::IGNORED;
+        if include_index {
+          dirty_flags |= Status::INDEX_NEW | Status::INDEX_MODIFIED | Status::INDEX_TYPECHANGE;
+        }
+        status.intersects(dirty_flags)
+      }
+      // files outside of the working tree can't be recovered from git at all
+      Err(_) => true,
+    };
+
+    if is_dirty {
+      dirty_files.push(dest.to_path_buf());
+    }
+  }
+
+  dirty_files
+}
+
+// refuses to overwrite `dirty_files` unless forced, and when forced, journals
+// them as a clean first so the operation can give them back
+fn save_dirty_files(
+  repo: &Repository,
+  workdir: &Path,
+  dirty_files: Vec<PathBuf>,
+  force: bool,
+  action: &str,
+  op: &mut PendingOperation,
+) -> Result<(), Error> {
+  if dirty_files.is_empty() {
+    return Ok(());
+  }
+
+  if !force {
+    for dest in &dirty_files {
+      eprintln!(
+        "{}: {} has local modifications",
+        "error".red(),
+        dest.display()
+      );
+    }
+    eprintln!(
+      "Refusing to overwrite them; use --force to {} anyway",
+      action
+    );
+    exit(exitcode::DATAERR);
+  }
+
+  let mut cleaned_files = Vec::new();
+  for dest in dirty_files {
+    // a directory can't be saved as a blob, nor be overwritten by one
+    if fs::symlink_metadata(&dest)
+      .map(|m| m.is_dir())
+      .unwrap_or(false)
+    {
+      return Err(format_err!("{} is a directory", dest.display()));
+    }
+
+    let oid = repo.write_blob(&dest)?;
+    let mode = get_file_mode(&dest)?;
+    let path = dest
+      .strip_prefix(workdir)
+      .unwrap_or(&dest)
+      .to_string_lossy()
+      .into_owned();
+    cleaned_files.push(CleanedFile { path, oid, mode });
+  }
+
+  let id = journal::record_clean(repo, &cleaned_files)?;
+  op.set_clean(id);
+  println!("Saved overwritten files as clean {}", id.to_string().cyan());
+
+  Ok(())
+}
+
+// looks up clean `id` and makes sure its files can be written back without
+// losing anything, before anything is actually changed
+fn prepare_clean_restore(
+  repo: &Repository,
+  id: usize,
+  force: bool,
+  action: &str,
+  op: &mut PendingOperation,
+) -> Result<CleanRecord, Error> {
+  let workdir = repo
+    .workdir()
+    .ok_or_else(|| failure::err_msg("repository is bare"))?;
+
+  let record = journal::find_clean(repo, id)?;
+
+  let dests: Vec<_> = record
+    .files
+    .iter()
+    .map(|file| workdir.join(&file.path))
+    .collect();
+  let dests: Vec<_> = dests.iter().map(PathBuf::as_path).collect();
+  let dirty_files = find_dirty_files(repo, workdir, &dests, false);
+  save_dirty_files(repo, workdir, dirty_files, force, action, op)?;
+
+  Ok(record)
+}
+
+fn write_clean(repo: &Repository, record: &CleanRecord) -> Result<(), Error> {
+  let workdir = repo
+    .workdir()
+    .ok_or_else(|| failure::err_msg("repository is bare"))?;
+
+  for file in &record.files {
+    repo
+      .restore_blob(file.oid, file.mode, &workdir.join(&file.path))
+      .with_context(|_| format!("couldn't restore {}", file.path))?;
+    println!("{}", repo.highlight_named_oid(&file.path, file.oid));
+  }
+
+  Ok(())
+}
+
+fn restore_from_clean(repo: &Repository, id: usize, force: bool) -> Result<(), Error> {
+  let mut op = PendingOperation::new(&format!("restore --from-clean {}", id));
+  let record = prepare_clean_restore(repo, id, force, "restore", &mut op)?;
+  write_clean(repo, &record)?;
+  op.record(repo)?;
+
+  Ok(())
+}
+
 pub fn show(globals: cli::Global, args: cli::Show) -> Result<(), Error> {
+  if args.list_themes {
+    for name in highlight::theme_names() {
+      println!("{}", name);
+    }
+    return Ok(());
+  }
+
   let repo =
     Repository::discover(globals.repo_path).with_context(|_| "couldn't open repository")?;
 
@@ -479,15 +1221,70 @@ pub fn show(globals: cli::Global, args: cli::Show) -> Result<(), Error> {
     .find_from_name(&args.name)
     .with_context(|_| "couldn't look up object")?;
 
-  repo.print_object(&object);
+  match object.as_blob() {
+    Some(blob) if args.raw => {
+      io::stdout()
+        .write_all(blob.content())
+        .with_context(|_| "couldn't write blob")?;
+    }
+    Some(blob) if args.hex => {
+      println!("{}", repo.highlight_named_oid("blob", object.id()));
+      binary::print_hexdump(blob.content()).with_context(|_| "couldn't write hex dump")?;
+    }
+    Some(blob) if blob.is_binary() => {
+      println!("{}", repo.highlight_named_oid("blob", object.id()));
+      repo.print_blob_summary(blob);
+    }
+    Some(blob) if pager::stdout_is_tty() => {
+      let path = split_label_path(&args.name).map(|(_, path)| path);
+
+      println!("{}", repo.highlight_named_oid("blob", object.id()));
+      print_blob_contents(
+        &repo,
+        blob,
+        path.unwrap_or_else(|| Path::new("")),
+        args.theme,
+      )?;
+    }
+    _ => repo.print_object(&object),
+  }
 
   Ok(())
 }
 
+// prints a text blob, syntax highlighted by its path when going to a terminal
+fn print_blob_contents(
+  repo: &Repository,
+  blob: &Blob,
+  path: &Path,
+  theme: Option<String>,
+) -> Result<(), Error> {
+  // highlighting is only for humans; piped output stays plain
+  if !pager::stdout_is_tty() {
+    io::stdout()
+      .write_all(blob.content())
+      .with_context(|_| "couldn't write blob")?;
+    return Ok(());
+  }
+
+  let theme = match theme {
+    Some(theme) => theme,
+    None => repo
+      .config()
+      .and_then(|config| config.get_string("milk.theme"))
+      .unwrap_or_else(|_| highlight::DEFAULT_THEME.to_string()),
+  };
+
+  highlight::print_highlighted(blob.content(), Some(path), &theme)
+}
+
 pub fn stage(globals: cli::Global, args: cli::Stage) -> Result<(), Error> {
   let repo =
     Repository::discover(globals.repo_path).with_context(|_| "couldn't open repository")?;
 
+  let mut op = PendingOperation::new(&format!("stage {}", args.paths.join(" ")));
+  op.track_index(&repo);
+
   let mut index = repo.index().with_context(|_| "couldn't open index")?;
 
   for path in args.paths {
@@ -499,27 +1296,154 @@ pub fn stage(globals: cli::Global, args: cli::Stage) -> Result<(), Error> {
 
   index.write().with_context(|_| "couldn't write index")?;
 
+  op.record(&repo)?;
+
+  Ok(())
+}
+
+fn print_status(repo: &Repository, quiet: bool, args: &cli::Status) -> Result<(), Error> {
+  let renames = rename_detection(repo, &args.renames)?;
+  let statuses = status::read_statuses(repo, !args.hide_untracked, args.show_ignored, &renames)?;
+
+  if args.porcelain {
+    return status::print_porcelain(repo, &statuses, args.nul);
+  }
+
+  if !quiet {
+    status::print_state_banner(repo);
+    status::print_header(repo, &statuses)?;
+    if !statuses.is_empty() {
+      println!();
+    }
+  }
+
+  let changes = status::collect_changes(repo, &statuses)?;
+  if args.tree {
+    status::print_tree(&changes);
+  } else if args.group {
+    status::print_grouped(&changes);
+  } else {
+    status::print_flat(&changes);
+  }
+
   Ok(())
 }
 
 pub fn status(globals: cli::Global, args: cli::Status) -> Result<(), Error> {
-  let mut status_opts = StatusOptions::new();
-  status_opts.include_untracked(!args.hide_untracked);
-  status_opts.include_ignored(args.show_ignored);
+  let quiet = globals.quiet;
+  let repo =
+    Repository::discover(globals.repo_path).with_context(|_| "couldn't open repository")?;
+
+  if args.watch {
+    return status::watch(&repo, || print_status(&repo, quiet, &args));
+  }
+
+  print_status(&repo, quiet, &args)
+}
+
+fn find_submodules<'repo>(
+  repo: &'repo Repository,
+  names: &[String],
+) -> Result<Vec<Submodule<'repo>>, Error> {
+  if names.is_empty() {
+    let submodules = repo
+      .submodules()
+      .with_context(|_| "couldn't list submodules")?;
+    return Ok(submodules);
+  }
+
+  let mut submodules = Vec::new();
+  for name in names {
+    let submodule = repo
+      .find_submodule(name)
+      .with_context(|_| format!("couldn't find submodule `{}`", name))?;
+    submodules.push(submodule);
+  }
 
+  Ok(submodules)
+}
+
+pub fn submodule_init(globals: cli::Global, args: cli::SubmoduleInit) -> Result<(), Error> {
   let repo =
     Repository::discover(globals.repo_path).with_context(|_| "couldn't open repository")?;
 
-  let statuses = repo
-    .statuses(Some(&mut status_opts))
-    .with_context(|_| "couldn't open status")?;
+  for mut submodule in find_submodules(&repo, &args.names)? {
+    submodule
+      .init(args.force)
+      .with_context(|_| "couldn't initialize submodule")?;
 
-  for entry in statuses.iter() {
-    let path = entry.path().unwrap_or("[invalid utf-8]");
-    let status = entry.status();
-    let status_string = get_status_string(status);
+    println!(
+      "Initialized @{} {}",
+      submodule.name().unwrap_or("[invalid utf-8]").bright_red(),
+      submodule.url().unwrap_or("[no url]").bright_black()
+    );
+  }
 
-    println!("{} {}", status_string, path);
+  Ok(())
+}
+
+pub fn submodule_ls(globals: cli::Global, _args: cli::SubmoduleLs) -> Result<(), Error> {
+  let repo =
+    Repository::discover(globals.repo_path).with_context(|_| "couldn't open repository")?;
+
+  let submodules = repo
+    .submodules()
+    .with_context(|_| "couldn't list submodules")?;
+
+  for submodule in submodules {
+    let name = submodule.name().unwrap_or("[invalid utf-8]");
+    let path = submodule.path().to_str().unwrap_or("[invalid utf-8]");
+
+    let location = if name == path {
+      format!("@{}", name.bright_red())
+    } else {
+      format!("@{} {}", name.bright_red(), path)
+    };
+
+    println!(
+      "{} {} {}",
+      location,
+      repo.get_submodule_summary(&submodule)?,
+      submodule.url().unwrap_or("[no url]").bright_black()
+    );
+  }
+
+  Ok(())
+}
+
+pub fn submodule_sync(globals: cli::Global, args: cli::SubmoduleSync) -> Result<(), Error> {
+  let repo =
+    Repository::discover(globals.repo_path).with_context(|_| "couldn't open repository")?;
+
+  for mut submodule in find_submodules(&repo, &args.names)? {
+    submodule
+      .sync()
+      .with_context(|_| "couldn't sync submodule")?;
+
+    println!(
+      "Synced @{} {}",
+      submodule.name().unwrap_or("[invalid utf-8]").bright_red(),
+      submodule.url().unwrap_or("[no url]").bright_black()
+    );
+  }
+
+  Ok(())
+}
+
+pub fn submodule_update(globals: cli::Global, args: cli::SubmoduleUpdate) -> Result<(), Error> {
+  let repo =
+    Repository::discover(globals.repo_path).with_context(|_| "couldn't open repository")?;
+
+  for mut submodule in find_submodules(&repo, &args.names)? {
+    submodule
+      .update(args.init, None)
+      .with_context(|_| "couldn't update submodule")?;
+
+    println!(
+      "Updated @{} {}",
+      submodule.name().unwrap_or("[invalid utf-8]").bright_red(),
+      repo.get_submodule_summary(&submodule)?
+    );
   }
 
   Ok(())
@@ -534,10 +1458,15 @@ pub fn tag(globals: cli::Global, args: cli::Tag) -> Result<(), Error> {
     .with_context(|_| "couldn't look up object")?;
   repo.print_object(&object);
 
+  let mut op = PendingOperation::new(&format!("tag {}", args.tag_name));
+  op.track_ref(&repo, &format!("refs/tags/{}", args.tag_name));
+
   repo
     .tag_lightweight(&args.tag_name, &object, false)
     .with_context(|_| "couldn't create tag")?;
 
+  op.record(&repo)?;
+
   Ok(())
 }
 
@@ -550,6 +1479,9 @@ pub fn unstage(globals: cli::Global, args: cli::Unstage) -> Result<(), Error> {
     .peel(ObjectType::Any)
     .with_context(|_| "couldn't peel to commit HEAD")?;
 
+  let mut op = PendingOperation::new(&format!("unstage {}", args.paths.join(" ")));
+  op.track_index(&repo);
+
   if !args.paths.is_empty() {
     repo
       .reset_default(Some(&commit), args.paths)
@@ -560,14 +1492,150 @@ pub fn unstage(globals: cli::Global, args: cli::Unstage) -> Result<(), Error> {
       .with_context(|_| "could not reset to HEAD")?;
   }
 
+  op.record(&repo)?;
+
+  Ok(())
+}
+
+pub fn undo(globals: cli::Global, args: cli::Undo) -> Result<(), Error> {
+  let repo =
+    Repository::discover(globals.repo_path).with_context(|_| "couldn't open repository")?;
+
+  let operations = journal::read_operations(&repo)?;
+  let operation = match args.id {
+    Some(id) => operations.iter().find(|op| op.id == id),
+    None => operations.last(),
+  };
+  let operation = match operation {
+    Some(operation) => operation,
+    None => {
+      eprintln!("No such operation to undo");
+      exit(exitcode::USAGE);
+    }
+  };
+
+  // refuse to clobber anything that has moved on since the operation, since
+  // undoing it would silently throw that work away
+  if !args.force {
+    for change in &operation.refs {
+      if repo.refname_to_id(&change.name).ok() != change.new {
+        eprintln!(
+          "{} has changed since operation {}; use --force to undo anyway",
+          change.name, operation.id
+        );
+        exit(exitcode::DATAERR);
+      }
+    }
+
+    if let Some((_, new_tree)) = operation.index {
+      let mut index = repo.index().with_context(|_| "couldn't open index")?;
+      if index.write_tree().ok() != Some(new_tree) {
+        eprintln!(
+          "The index has changed since operation {}; use --force to undo anyway",
+          operation.id
+        );
+        exit(exitcode::DATAERR);
+      }
+    }
+  }
+
+  let mut op = PendingOperation::new(&format!("undo {}", operation.id));
+  for change in &operation.refs {
+    op.track_ref(&repo, &change.name);
+  }
+  if operation.index.is_some() {
+    op.track_index(&repo);
+  }
+
+  // checked before touching any refs so a refusal doesn't leave the undo
+  // half done
+  let clean = match operation.clean {
+    Some(clean) => Some(prepare_clean_restore(
+      &repo, clean, args.force, "undo", &mut op,
+    )?),
+    None => None,
+  };
+
+  let log_message = format!("milk: undo operation {}", operation.id);
+  for change in &operation.refs {
+    match change.old {
+      Some(old) => {
+        repo
+          .reference(&change.name, old, true, &log_message)
+          .with_context(|_| format!("couldn't restore {}", change.name))?;
+      }
+      None => {
+        // deleting it as a branch also drops its config, like its upstream
+        if let Ok(mut reference) = repo.find_reference(&change.name) {
+          let result = if reference.is_branch() {
+            Branch::wrap(reference).delete()
+          } else {
+            reference.delete()
+          };
+          result.with_context(|_| format!("couldn't remove {}", change.name))?;
+        }
+      }
+    }
+    println!(
+      "{} {} -> {}",
+      change.name.cyan(),
+      describe_optional_oid(&repo, change.new),
+      describe_optional_oid(&repo, change.old)
+    );
+  }
+
+  if let Some((old_tree, _)) = operation.index {
+    let tree = repo
+      .find_tree(old_tree)
+      .with_context(|_| "couldn't find index tree")?;
+    let mut index = repo.index().with_context(|_| "couldn't open index")?;
+    index
+      .read_tree(&tree)
+      .with_context(|_| "couldn't read tree into index")?;
+    index.write().with_context(|_| "couldn't write index")?;
+    println!("{} restored", "index".cyan());
+  }
+
+  if let Some(record) = clean {
+    write_clean(&repo, &record)?;
+  }
+
+  let id = op.record(&repo)?;
+  if !globals.quiet {
+    println!(
+      "Undid operation {}: {}",
+      operation.id.to_string().cyan(),
+      operation.description
+    );
+    println!("This was recorded as operation {}", id.to_string().cyan());
+  }
+
   Ok(())
 }
 
-pub fn where_(globals: cli::Global, _args: cli::Where) -> Result<(), Error> {
+fn describe_optional_oid(repo: &Repository, oid: Option<Oid>) -> String {
+  match oid {
+    Some(oid) => format!("{}", repo.get_short_id(oid).bright_black()),
+    None => format!("{}", "[none]".bright_black()),
+  }
+}
+
+pub fn where_(globals: cli::Global, args: cli::Where) -> Result<(), Error> {
   let repo =
     Repository::discover(globals.repo_path).with_context(|_| "couldn't open repository")?;
 
-  match repo.workdir() {
+  let main_repo = if repo.is_worktree() {
+    Some(repo.open_main_worktree()?)
+  } else {
+    None
+  };
+
+  let shown_repo = match (&main_repo, args.main) {
+    (Some(main_repo), true) => main_repo,
+    _ => &repo,
+  };
+
+  match shown_repo.workdir() {
     Some(path) => match path.to_str() {
       Some(path_str) => println!("{}", path_str),
       None => println!("Path is not UTF-8"),
@@ -575,5 +1643,253 @@ pub fn where_(globals: cli::Global, _args: cli::Where) -> Result<(), Error> {
     None => println!("Repository is bare."),
   }
 
+  if let (Some(main_repo), false, false) = (&main_repo, args.main, globals.quiet) {
+    let worktree =
+      Worktree::open_from_repository(&repo).with_context(|_| "couldn't open worktree")?;
+    let name = worktree.name().unwrap_or("[invalid utf-8]");
+    let main_path = main_repo
+      .workdir()
+      .map(|path| path.display().to_string())
+      .unwrap_or_else(|| "[bare]".to_string());
+    println!(
+      "{} linked worktree of {}",
+      name.cyan(),
+      main_path.bright_black()
+    );
+  }
+
+  Ok(())
+}
+
+pub fn worktree_lock(globals: cli::Global, args: cli::WorktreeLock) -> Result<(), Error> {
+  let repo =
+    Repository::discover(globals.repo_path).with_context(|_| "couldn't open repository")?;
+
+  let worktree = repo
+    .find_worktree(&args.name)
+    .with_context(|_| "couldn't find worktree")?;
+
+  worktree
+    .lock(args.reason.as_deref())
+    .with_context(|_| "couldn't lock worktree")?;
+
+  println!("Locked worktree {}", args.name.cyan());
+
+  Ok(())
+}
+
+pub fn worktree_ls(globals: cli::Global, _args: cli::WorktreeLs) -> Result<(), Error> {
+  let repo =
+    Repository::discover(globals.repo_path).with_context(|_| "couldn't open repository")?;
+  let main_repo = repo.open_main_worktree()?;
+
+  let current_path = repo.workdir().map(Path::to_path_buf);
+
+  if let Some(path) = main_repo.workdir() {
+    let head_prefix = if current_path.as_deref() == Some(path) {
+      "*"
+    } else {
+      " "
+    };
+    println!(
+      "{} {} {} {}",
+      head_prefix,
+      "(main)".cyan(),
+      path.display(),
+      describe_worktree_head(&main_repo)?
+    );
+  }
+
+  let names = main_repo
+    .worktrees()
+    .with_context(|_| "couldn't list worktrees")?;
+
+  for name in names.iter() {
+    let name = name.unwrap_or("[invalid utf-8]");
+    let worktree = main_repo
+      .find_worktree(name)
+      .with_context(|_| "couldn't find worktree")?;
+    let path = worktree.path();
+
+    let head_prefix = if current_path.as_deref() == Some(path) {
+      "*"
+    } else {
+      " "
+    };
+
+    let lock_marker = match worktree.is_locked() {
+      Ok(WorktreeLockStatus::Locked(_)) => format!(" {}", "locked".yellow()),
+      _ => String::new(),
+    };
+
+    let description = if worktree.validate().is_ok() {
+      let worktree_repo =
+        Repository::open_from_worktree(&worktree).with_context(|_| "couldn't open worktree")?;
+      describe_worktree_head(&worktree_repo)?
+    } else {
+      format!("{}", "missing".red())
+    };
+
+    println!(
+      "{} {} {} {}{}",
+      head_prefix,
+      name.cyan(),
+      path.display(),
+      description,
+      lock_marker
+    );
+  }
+
+  Ok(())
+}
+
+fn describe_worktree_head(repo: &Repository) -> Result<String, Error> {
+  let head = match repo.head() {
+    Ok(head) => head,
+    Err(_) => return Ok(format!("{}", "[no commits]".bright_black())),
+  };
+
+  let head_name = if repo.head_detached().unwrap_or(false) {
+    "[detached]"
+  } else {
+    head.shorthand().unwrap_or("[???]")
+  };
+
+  let description = match head.target() {
+    Some(oid) => repo.highlight_named_oid(head_name, oid),
+    None => format!("{}", head_name.cyan()),
+  };
+
+  if repo.is_dirty(false)? {
+    Ok(format!("{} {}", description, "dirty".red()))
+  } else {
+    Ok(description)
+  }
+}
+
+pub fn worktree_new(globals: cli::Global, args: cli::WorktreeNew) -> Result<(), Error> {
+  let repo =
+    Repository::discover(globals.repo_path).with_context(|_| "couldn't open repository")?;
+
+  let branch = match &args.branch {
+    Some(branch_name) => Some(
+      repo
+        .find_branch(branch_name, BranchType::Local)
+        .with_context(|_| "couldn't find branch")?,
+    ),
+    None => None,
+  };
+
+  let mut opts = WorktreeAddOptions::new();
+  opts.lock(args.lock);
+  if let Some(branch) = &branch {
+    opts.reference(Some(branch.get()));
+  }
+
+  let worktree = repo
+    .worktree(&args.name, &args.path, Some(&opts))
+    .with_context(|_| "couldn't create worktree")?;
+
+  let worktree_repo =
+    Repository::open_from_worktree(&worktree).with_context(|_| "couldn't open worktree")?;
+
+  println!(
+    "Created worktree {} at {}",
+    args.name.cyan(),
+    worktree.path().display()
+  );
+  println!("{}", describe_worktree_head(&worktree_repo)?);
+
+  Ok(())
+}
+
+pub fn worktree_prune(globals: cli::Global, _args: cli::WorktreePrune) -> Result<(), Error> {
+  let repo =
+    Repository::discover(globals.repo_path).with_context(|_| "couldn't open repository")?;
+
+  let names = repo
+    .worktrees()
+    .with_context(|_| "couldn't list worktrees")?;
+
+  for name in names.iter() {
+    let name = name.unwrap_or("[invalid utf-8]");
+    let worktree = repo
+      .find_worktree(name)
+      .with_context(|_| "couldn't find worktree")?;
+
+    // with default options, only worktrees whose directories are gone and
+    // which aren't locked are prunable
+    if worktree
+      .is_prunable(None)
+      .with_context(|_| "couldn't check worktree")?
+    {
+      worktree
+        .prune(None)
+        .with_context(|_| "couldn't prune worktree")?;
+      println!("Pruned worktree {}", name.cyan());
+    }
+  }
+
+  Ok(())
+}
+
+pub fn worktree_rm(globals: cli::Global, args: cli::WorktreeRm) -> Result<(), Error> {
+  let repo =
+    Repository::discover(globals.repo_path).with_context(|_| "couldn't open repository")?;
+
+  let worktree = repo
+    .find_worktree(&args.name)
+    .with_context(|_| "couldn't find worktree")?;
+
+  if !args.force {
+    if let Ok(WorktreeLockStatus::Locked(_)) = worktree.is_locked() {
+      eprintln!(
+        "Worktree `{}` is locked; use --force to remove it",
+        args.name
+      );
+      exit(exitcode::USAGE);
+    }
+
+    if worktree.validate().is_ok() {
+      let worktree_repo =
+        Repository::open_from_worktree(&worktree).with_context(|_| "couldn't open worktree")?;
+      if worktree_repo.is_dirty(true)? {
+        eprintln!(
+          "Worktree `{}` has local modifications; use --force to remove it",
+          args.name
+        );
+        exit(exitcode::USAGE);
+      }
+    }
+  }
+
+  let mut prune_opts = WorktreePruneOptions::new();
+  prune_opts.valid(true);
+  prune_opts.locked(args.force);
+  prune_opts.working_tree(true);
+
+  worktree
+    .prune(Some(&mut prune_opts))
+    .with_context(|_| "couldn't remove worktree")?;
+
+  println!("Removed worktree {}", args.name.cyan());
+
+  Ok(())
+}
+
+pub fn worktree_unlock(globals: cli::Global, args: cli::WorktreeUnlock) -> Result<(), Error> {
+  let repo =
+    Repository::discover(globals.repo_path).with_context(|_| "couldn't open repository")?;
+
+  let worktree = repo
+    .find_worktree(&args.name)
+    .with_context(|_| "couldn't find worktree")?;
+
+  worktree
+    .unlock()
+    .with_context(|_| "couldn't unlock worktree")?;
+
+  println!("Unlocked worktree {}", args.name.cyan());
+
   Ok(())
 }
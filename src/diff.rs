@@ -0,0 +1,905 @@
+use colored::*;
+use failure::Error;
+use failure::ResultExt;
+use git2::Delta;
+use git2::Diff;
+use git2::DiffDelta;
+use git2::DiffFormat;
+use git2::Patch;
+
+/// How finely paired lines are compared when highlighting changes within them.
+#[derive(Clone, Copy, Debug)]
+pub enum Granularity {
+  Word,
+  Char,
+}
+
+// pairs whose contents have less than this much in common are shown as plain
+// removed/added lines, since highlighting nearly everything doesn't help
+const MIN_SIMILARITY: f64 = 0.3;
+
+// keeps the LCS table from getting out of hand on minified files and such
+const MAX_LCS_CELLS: usize = 250_000;
+
+fn print_line(origin: char, display: &str) {
+  match origin {
+    '+' => print!("{}{}", "+".green(), display.green()),
+    '-' => print!("{}{}", "-".red(), display.red()),
+    ' ' => print!(" {}", display.white()),
+    _ => print!("{}", display.cyan()),
+  }
+}
+
+/// Prints `diff` as a colored patch. With a granularity, runs of removed lines
+/// are paired up with the added lines that follow them and only the words or
+/// characters that actually changed are highlighted.
+pub fn print_patch(diff: &Diff, granularity: Option<Granularity>) -> Result<(), Error> {
+  let mut removed = Vec::new();
+  let mut added = Vec::new();
+
+  // this API is literally insane
+  // example code yanked from here:
+  //   https://github.com/rust-lang/git2-rs/blob/master/examples/diff.rs#L153-L179
+  diff
+    .print(DiffFormat::Patch, |_delta, _hunk, line| {
+      let display = String::from_utf8_lossy(line.content()).into_owned();
+      match (line.origin(), granularity) {
+        ('-', Some(granularity)) => {
+          if !added.is_empty() {
+            print_paired_lines(&mut removed, &mut added, granularity);
+          }
+          removed.push(display);
+        }
+        ('+', Some(_)) => added.push(display),
+        (origin, _) => {
+          if let Some(granularity) = granularity {
+            print_paired_lines(&mut removed, &mut added, granularity);
+          }
+          print_line(origin, &display);
+        }
+      }
+      true
+    })
+    .with_context(|_| "failed to print diff")?;
+
+  if let Some(granularity) = granularity {
+    print_paired_lines(&mut removed, &mut added, granularity);
+  }
+
+  Ok(())
+}
+
+fn print_paired_lines(
+  removed: &mut Vec<String>,
+  added: &mut Vec<String>,
+  granularity: Granularity,
+) {
+  let highlights: Vec<_> = removed
+    .iter()
+    .zip(added.iter())
+    .map(|(old, new)| highlight_pair(old, new, granularity))
+    .collect();
+
+  for (i, line) in removed.iter().enumerate() {
+    match highlights.get(i) {
+      Some(Some((old, _))) => print!("{}{}", "-".red(), old),
+      _ => print_line('-', line),
+    }
+  }
+
+  for (i, line) in added.iter().enumerate() {
+    match highlights.get(i) {
+      Some(Some((_, new))) => print!("{}{}", "+".green(), new),
+      _ => print_line('+', line),
+    }
+  }
+
+  removed.clear();
+  added.clear();
+}
+
+/// Splits a line into the units that are compared. Words are runs of
+/// alphanumerics, runs of whitespace stick together, and all other characters
+/// stand alone.
+pub fn tokenize(line: &str, granularity: Granularity) -> Vec<&str> {
+  let mut tokens = Vec::new();
+  let mut start = 0;
+  let mut last_class = None;
+
+  for (i, c) in line.char_indices() {
+    let class = match granularity {
+      Granularity::Char => None,
+      Granularity::Word if c.is_alphanumeric() || c == '_' => Some(0),
+      Granularity::Word if c.is_whitespace() => Some(1),
+      Granularity::Word => None,
+    };
+
+    if i > start && (class.is_none() || class != last_class) {
+      tokens.push(&line[start..i]);
+      start = i;
+    }
+    last_class = class;
+  }
+
+  if start < line.len() {
+    tokens.push(&line[start..]);
+  }
+
+  tokens
+}
+
+/// Marks which tokens of `old` and `new` are not part of their longest common
+/// subsequence. Returns `None` if the inputs are too large to compare.
+pub fn changed_tokens(old: &[&str], new: &[&str]) -> Option<(Vec<bool>, Vec<bool>)> {
+  let (n, m) = (old.len(), new.len());
+  if n * m > MAX_LCS_CELLS {
+    return None;
+  }
+
+  let mut table = vec![vec![0u32; m + 1]; n + 1];
+  for i in (0..n).rev() {
+    for j in (0..m).rev() {
+      table[i][j] = if old[i] == new[j] {
+        table[i + 1][j + 1] + 1
+      } else {
+        table[i + 1][j].max(table[i][j + 1])
+      };
+    }
+  }
+
+  let mut old_changed = vec![true; n];
+  let mut new_changed = vec![true; m];
+  let (mut i, mut j) = (0, 0);
+  while i < n && j < m {
+    if old[i] == new[j] {
+      old_changed[i] = false;
+      new_changed[j] = false;
+      i += 1;
+      j += 1;
+    } else if table[i + 1][j] >= table[i][j + 1] {
+      i += 1;
+    } else {
+      j += 1;
+    }
+  }
+
+  Some((old_changed, new_changed))
+}
+
+/// A run of text that's either shared by both lines of a pair or unique to one.
+type Segment = (String, bool);
+
+// groups tokens into runs so that whole runs can be colored at once, which
+// keeps the escape codes down
+fn segments(tokens: &[&str], changed: &[bool]) -> Vec<Segment> {
+  let mut segments: Vec<Segment> = Vec::new();
+
+  for (token, &is_changed) in tokens.iter().zip(changed) {
+    match segments.last_mut() {
+      Some((run, run_changed)) if *run_changed == is_changed => run.push_str(token),
+      _ => segments.push((token.to_string(), is_changed)),
+    }
+  }
+
+  segments
+}
+
+fn render_segments(segments: &[Segment], plain: fn(&str) -> ColoredString) -> String {
+  let mut rendered = String::new();
+
+  for (run, is_changed) in segments {
+    let colored = plain(run);
+    let colored = if *is_changed {
+      colored.reversed()
+    } else {
+      colored
+    };
+    rendered.push_str(&colored.to_string());
+  }
+
+  rendered
+}
+
+/// Compares two lines (without their line endings) and splits each into the
+/// runs that changed and the runs that didn't. Returns `None` if the lines are
+/// too different or too large for highlighting to be useful.
+fn split_pair(
+  old: &str,
+  new: &str,
+  granularity: Granularity,
+) -> Option<(Vec<Segment>, Vec<Segment>)> {
+  let old_tokens = tokenize(old, granularity);
+  let new_tokens = tokenize(new, granularity);
+  let (old_changed, new_changed) = changed_tokens(&old_tokens, &new_tokens)?;
+
+  let common_len: usize = old_tokens
+    .iter()
+    .zip(&old_changed)
+    .filter(|(_, &is_changed)| !is_changed)
+    .map(|(token, _)| token.len())
+    .sum();
+  let total_len = old.len() + new.len();
+  if total_len > 0 && (2 * common_len) as f64 / (total_len as f64) < MIN_SIMILARITY {
+    return None;
+  }
+
+  Some((
+    segments(&old_tokens, &old_changed),
+    segments(&new_tokens, &new_changed),
+  ))
+}
+
+fn highlight_pair(old: &str, new: &str, granularity: Granularity) -> Option<(String, String)> {
+  let old_body = old.trim_end_matches('\n');
+  let new_body = new.trim_end_matches('\n');
+
+  let (old_segments, new_segments) = split_pair(old_body, new_body, granularity)?;
+  let old_rendered = render_segments(&old_segments, |s| s.red());
+  let new_rendered = render_segments(&new_segments, |s| s.green());
+
+  // keep the newline outside of the highlighting so it doesn't bleed
+  let old_newline = &old[old_body.len()..];
+  let new_newline = &new[new_body.len()..];
+
+  Some((
+    format!("{}{}", old_rendered, old_newline),
+    format!("{}{}", new_rendered, new_newline),
+  ))
+}
+
+/// Insertion and deletion counts for one file in a diff.
+pub struct FileStat {
+  pub path: String,
+  pub status: Delta,
+  pub insertions: usize,
+  pub deletions: usize,
+  pub binary: bool,
+}
+
+/// Describes the file a delta applies to, as `old -> new` if it moved.
+pub fn delta_path(delta: &DiffDelta) -> String {
+  let old_path = delta.old_file().path();
+  let new_path = delta.new_file().path();
+
+  match (old_path, new_path) {
+    (Some(old), Some(new)) if old != new => format!("{} -> {}", old.display(), new.display()),
+    (_, Some(path)) | (Some(path), None) => path.display().to_string(),
+    (None, None) => "[unknown]".to_string(),
+  }
+}
+
+pub fn get_delta_status_string(status: Delta) -> String {
+  let status_string = match status {
+    Delta::Added => "A".cyan(),
+    Delta::Deleted => "D".red(),
+    Delta::Modified => "M".green(),
+    Delta::Renamed => "R".blue(),
+    Delta::Copied => "C".blue(),
+    Delta::Typechange => "T".blue(),
+    Delta::Untracked => "?".bright_cyan(),
+    Delta::Ignored => "!".white(),
+    Delta::Conflicted => "U".red(),
+    _ => " ".normal(),
+  };
+
+  format!("{}", status_string)
+}
+
+pub fn file_stats(diff: &Diff) -> Result<Vec<FileStat>, Error> {
+  let mut stats = Vec::new();
+
+  for idx in 0..diff.deltas().len() {
+    let patch = Patch::from_diff(diff, idx).with_context(|_| "couldn't generate patch")?;
+    let delta = diff.get_delta(idx).unwrap();
+
+    let (insertions, deletions, binary) = match &patch {
+      Some(patch) if !patch.delta().flags().is_binary() => {
+        let (_, insertions, deletions) = patch
+          .line_stats()
+          .with_context(|_| "couldn't count lines")?;
+        (insertions, deletions, false)
+      }
+      _ => (0, 0, true),
+    };
+
+    stats.push(FileStat {
+      path: delta_path(&delta),
+      status: delta.status(),
+      insertions,
+      deletions,
+      binary,
+    });
+  }
+
+  Ok(stats)
+}
+
+fn terminal_width() -> usize {
+  term_size::dimensions().map(|(w, _)| w).unwrap_or(80)
+}
+
+// same as git: anything that changed gets at least one character, and the
+// largest change fills the whole graph
+fn scale(count: usize, max_count: usize, width: usize) -> usize {
+  if count == 0 || max_count <= width {
+    count
+  } else {
+    1 + count * (width - 1) / max_count
+  }
+}
+
+pub fn print_stat(diff: &Diff) -> Result<(), Error> {
+  let stats = file_stats(diff)?;
+  if stats.is_empty() {
+    return Ok(());
+  }
+
+  let width = terminal_width();
+  let max_count = stats
+    .iter()
+    .map(|stat| stat.insertions + stat.deletions)
+    .max()
+    .unwrap_or(0);
+  let count_width = max_count.to_string().len().max(3);
+
+  // long paths get their beginnings cut off so that the graph keeps some room
+  let max_name_width = (width / 2).max(20);
+  let name_width = stats
+    .iter()
+    .map(|stat| stat.path.chars().count())
+    .max()
+    .unwrap_or(0)
+    .min(max_name_width);
+
+  // " name | count graph"
+  let graph_width = width.saturating_sub(name_width + count_width + 5).max(10);
+
+  for stat in &stats {
+    let char_count = stat.path.chars().count();
+    let name = if char_count > name_width {
+      let tail: String = stat
+        .path
+        .chars()
+        .skip(char_count - name_width + 3)
+        .collect();
+      format!("...{}", tail)
+    } else {
+      stat.path.clone()
+    };
+
+    if stat.binary {
+      println!(
+        " {:name_width$} | {:>count_width$}",
+        name,
+        "Bin",
+        name_width = name_width,
+        count_width = count_width
+      );
+      continue;
+    }
+
+    let plus = scale(stat.insertions, max_count, graph_width);
+    let minus = scale(stat.deletions, max_count, graph_width);
+    let graph = format!("{}{}", "+".repeat(plus).green(), "-".repeat(minus).red());
+    println!(
+      " {:name_width$} | {:>count_width$}{}{}",
+      name,
+      stat.insertions + stat.deletions,
+      if plus + minus > 0 { " " } else { "" },
+      graph,
+      name_width = name_width,
+      count_width = count_width
+    );
+  }
+
+  let totals = diff.stats().with_context(|_| "couldn't total diff")?;
+  let mut summary = format!(
+    " {} file{} changed",
+    totals.files_changed(),
+    if totals.files_changed() == 1 { "" } else { "s" }
+  );
+  if totals.insertions() > 0 {
+    summary.push_str(&format!(
+      ", {} insertion{}(+)",
+      totals.insertions(),
+      if totals.insertions() == 1 { "" } else { "s" }
+    ));
+  }
+  if totals.deletions() > 0 {
+    summary.push_str(&format!(
+      ", {} deletion{}(-)",
+      totals.deletions(),
+      if totals.deletions() == 1 { "" } else { "s" }
+    ));
+  }
+  println!("{}", summary);
+
+  Ok(())
+}
+
+pub fn print_numstat(diff: &Diff) -> Result<(), Error> {
+  for stat in file_stats(diff)? {
+    if stat.binary {
+      println!("-\t-\t{}", stat.path);
+    } else {
+      println!("{}\t{}\t{}", stat.insertions, stat.deletions, stat.path);
+    }
+  }
+
+  Ok(())
+}
+
+pub fn print_name_status(diff: &Diff) -> Result<(), Error> {
+  for delta in diff.deltas() {
+    println!(
+      "{}\t{}",
+      get_delta_status_string(delta.status()),
+      delta_path(&delta)
+    );
+  }
+
+  Ok(())
+}
+
+const TAB_WIDTH: usize = 8;
+
+fn expand_tabs(line: &str) -> String {
+  let mut expanded = String::new();
+  let mut column = 0;
+
+  for c in line.chars() {
+    if c == '\t' {
+      let spaces = TAB_WIDTH - column % TAB_WIDTH;
+      expanded.push_str(&" ".repeat(spaces));
+      column += spaces;
+    } else {
+      expanded.push(c);
+      column += 1;
+    }
+  }
+
+  expanded
+}
+
+// cuts segments down to `width` characters, marking lines that got cut with
+// an ellipsis, and optionally pads them out so the columns line up
+fn fit_segments(segments: &[Segment], width: usize, pad: bool) -> Vec<Segment> {
+  let total: usize = segments.iter().map(|(run, _)| run.chars().count()).sum();
+  let mut fitted = Vec::new();
+
+  if total <= width {
+    fitted.extend(segments.iter().cloned());
+    if pad {
+      fitted.push((" ".repeat(width - total), false));
+    }
+    return fitted;
+  }
+
+  let mut remaining = width.saturating_sub(1);
+  for (run, is_changed) in segments {
+    if remaining == 0 {
+      break;
+    }
+    let taken: String = run.chars().take(remaining).collect();
+    remaining -= taken.chars().count();
+    fitted.push((taken, *is_changed));
+  }
+  fitted.push(("…".to_string(), false));
+
+  fitted
+}
+
+/// One side of a row in the side-by-side view.
+struct Cell {
+  origin: char,
+  lineno: Option<u32>,
+  segments: Vec<Segment>,
+}
+
+impl Cell {
+  fn plain(origin: char, lineno: Option<u32>, text: &str) -> Cell {
+    Cell {
+      origin,
+      lineno,
+      segments: vec![(text.to_string(), false)],
+    }
+  }
+}
+
+struct Columns {
+  number_width: usize,
+  text_width: usize,
+}
+
+impl Columns {
+  fn render(&self, cell: Option<&Cell>, pad: bool) -> String {
+    let cell = match cell {
+      Some(cell) => cell,
+      None if pad => return " ".repeat(self.number_width + 1 + self.text_width),
+      None => return String::new(),
+    };
+
+    let plain: fn(&str) -> ColoredString = match cell.origin {
+      '-' => |s| s.red(),
+      '+' => |s| s.green(),
+      _ => |s| s.white(),
+    };
+    let number = cell.lineno.map(|n| n.to_string()).unwrap_or_default();
+
+    format!(
+      "{} {}",
+      format!("{:>width$}", number, width = self.number_width).dimmed(),
+      render_segments(&fit_segments(&cell.segments, self.text_width, pad), plain)
+    )
+  }
+
+  fn print_row(&self, old: Option<&Cell>, new: Option<&Cell>) {
+    println!(
+      "{} {} {}",
+      self.render(old, true),
+      "│".dimmed(),
+      self.render(new, false)
+    );
+  }
+
+  // lines the removed run up with the added run that replaced it, row by row
+  fn print_changes(
+    &self,
+    removed: &mut Vec<(Option<u32>, String)>,
+    added: &mut Vec<(Option<u32>, String)>,
+    granularity: Granularity,
+  ) {
+    for i in 0..removed.len().max(added.len()) {
+      let (old, new) = match (removed.get(i), added.get(i)) {
+        (Some((old_lineno, old_text)), Some((new_lineno, new_text))) => {
+          match split_pair(old_text, new_text, granularity) {
+            Some((old_segments, new_segments)) => (
+              Some(Cell {
+                origin: '-',
+                lineno: *old_lineno,
+                segments: old_segments,
+              }),
+              Some(Cell {
+                origin: '+',
+                lineno: *new_lineno,
+                segments: new_segments,
+              }),
+            ),
+            None => (
+              Some(Cell::plain('-', *old_lineno, old_text)),
+              Some(Cell::plain('+', *new_lineno, new_text)),
+            ),
+          }
+        }
+        (Some((lineno, text)), None) => (Some(Cell::plain('-', *lineno, text)), None),
+        (None, Some((lineno, text))) => (None, Some(Cell::plain('+', *lineno, text))),
+        (None, None) => (None, None),
+      };
+
+      self.print_row(old.as_ref(), new.as_ref());
+    }
+
+    removed.clear();
+    added.clear();
+  }
+}
+
+/// Prints `diff` with the old and new contents next to each other, split
+/// evenly across the terminal.
+pub fn print_side_by_side(diff: &Diff, granularity: Granularity) -> Result<(), Error> {
+  let width = terminal_width();
+
+  for idx in 0..diff.deltas().len() {
+    let patch = Patch::from_diff(diff, idx).with_context(|_| "couldn't generate patch")?;
+    let delta = diff.get_delta(idx).unwrap();
+
+    println!(
+      "{} {}",
+      get_delta_status_string(delta.status()),
+      delta_path(&delta).bold()
+    );
+
+    let patch = match patch {
+      Some(ref patch) if !patch.delta().flags().is_binary() => patch,
+      _ => {
+        println!("{}", "binary files differ".cyan());
+        continue;
+      }
+    };
+
+    // the widest line number decides the gutter for the whole file
+    let mut max_lineno = 0;
+    for hunk_idx in 0..patch.num_hunks() {
+      let (hunk, _) = patch
+        .hunk(hunk_idx)
+        .with_context(|_| "couldn't read hunk")?;
+      max_lineno = max_lineno
+        .max(hunk.old_start() + hunk.old_lines())
+        .max(hunk.new_start() + hunk.new_lines());
+    }
+
+    // "123 old text │ 123 new text"
+    let number_width = max_lineno.to_string().len();
+    let columns = Columns {
+      number_width,
+      text_width: (width.saturating_sub(2 * (number_width + 1) + 3) / 2).max(10),
+    };
+
+    for hunk_idx in 0..patch.num_hunks() {
+      let (hunk, line_count) = patch
+        .hunk(hunk_idx)
+        .with_context(|_| "couldn't read hunk")?;
+      let header = String::from_utf8_lossy(hunk.header());
+      println!("{}", header.trim_end().cyan());
+
+      let mut removed = Vec::new();
+      let mut added = Vec::new();
+
+      for line_idx in 0..line_count {
+        let line = patch
+          .line_in_hunk(hunk_idx, line_idx)
+          .with_context(|_| "couldn't read line")?;
+        let content = String::from_utf8_lossy(line.content());
+        let text = expand_tabs(content.trim_end_matches(['\n', '\r']));
+
+        match line.origin() {
+          '-' => {
+            if !added.is_empty() {
+              columns.print_changes(&mut removed, &mut added, granularity);
+            }
+            removed.push((line.old_lineno(), text));
+          }
+          '+' => added.push((line.new_lineno(), text)),
+          ' ' => {
+            columns.print_changes(&mut removed, &mut added, granularity);
+            columns.print_row(
+              Some(&Cell::plain(' ', line.old_lineno(), &text)),
+              Some(&Cell::plain(' ', line.new_lineno(), &text)),
+            );
+          }
+          // the "no newline at end of file" markers don't get a row
+          _ => {}
+        }
+      }
+
+      columns.print_changes(&mut removed, &mut added, granularity);
+    }
+  }
+
+  Ok(())
+}
+
+// git's default funcname heuristic: a line that starts with a letter, `_` or
+// `$` begins a new function
+fn is_function_start(content: &[u8]) -> bool {
+  match content.first() {
+    Some(&c) => c.is_ascii_alphabetic() || c == b'_' || c == b'$',
+    None => false,
+  }
+}
+
+// one line of a hunk that holds a whole file
+struct FullLine {
+  origin: char,
+  content: Vec<u8>,
+  old_lineno: Option<u32>,
+  new_lineno: Option<u32>,
+}
+
+impl FullLine {
+  fn is_old(&self) -> bool {
+    self.origin != '+'
+  }
+
+  fn is_new(&self) -> bool {
+    self.origin != '-'
+  }
+
+  fn is_blank(&self) -> bool {
+    self.content.iter().all(u8::is_ascii_whitespace)
+  }
+
+  fn is_function(&self) -> bool {
+    self.is_old() && is_function_start(&self.content)
+  }
+}
+
+// the ranges of `lines` that get a hunk when every change is shown along with
+// the function around it. this follows what git's xdiff does for -W: before a
+// change, back up to the function it's in along with the comment right above
+// it, and after it, keep going until just before the next function. function
+// lines are only looked for in the old file.
+fn function_ranges(lines: &[FullLine], context: usize) -> Vec<(usize, usize)> {
+  let len = lines.len();
+  let next_old = |pos: usize| (pos..len).find(|&pos| lines[pos].is_old());
+  let prev_old = |pos: usize| (0..pos).rev().find(|&pos| lines[pos].is_old());
+
+  let mut changes = Vec::new();
+  let mut pos = 0;
+  while pos < len {
+    if lines[pos].origin == ' ' {
+      pos += 1;
+      continue;
+    }
+    let start = pos;
+    while pos < len && lines[pos].origin != ' ' {
+      pos += 1;
+    }
+    changes.push((start, pos));
+  }
+
+  let function_start = |change_start: usize| -> Option<usize> {
+    let from = match next_old(change_start) {
+      Some(from) => from,
+      // appended to the end: a whole new function doesn't need anything else
+      None
+        if (change_start..len)
+          .any(|pos| lines[pos].is_new() && is_function_start(&lines[pos].content)) =>
+      {
+        return None
+      }
+      None => prev_old(len)?,
+    };
+
+    let mut start = (0..=from)
+      .rev()
+      .find(|&pos| lines[pos].is_function())
+      .unwrap_or(0);
+    while let Some(prev) = prev_old(start) {
+      if lines[prev].is_blank() || lines[prev].is_function() {
+        break;
+      }
+      start = prev;
+    }
+    Some(start)
+  };
+
+  let function_end = |change_end: usize| -> usize {
+    let next_function =
+      next_old(change_end).and_then(|from| (from..len).find(|&pos| lines[pos].is_function()));
+    match next_function {
+      Some(mut end) => {
+        while let Some(prev) = prev_old(end) {
+          if !lines[prev].is_blank() {
+            break;
+          }
+          end = prev;
+        }
+        end
+      }
+      None => len,
+    }
+  };
+
+  let mut ranges = Vec::new();
+  let mut idx = 0;
+  while idx < changes.len() {
+    let mut start = changes[idx].0.saturating_sub(context);
+    if let Some(function_start) = function_start(changes[idx].0) {
+      start = start.min(function_start);
+    }
+
+    // changes close enough together, or in the same function, share a hunk
+    let mut end;
+    loop {
+      end = (changes[idx].1 + context)
+        .min(len)
+        .max(function_end(changes[idx].1));
+      let next_start = match changes.get(idx + 1) {
+        Some(&(next_start, _)) => next_start,
+        None => break,
+      };
+      let last = next_old(next_start).or_else(|| prev_old(len)).unwrap_or(0);
+      let between = match next_old(end) {
+        Some(end) => (end + 1..=last).any(|pos| lines[pos].is_function()),
+        None => false,
+      };
+      if next_start.saturating_sub(context) <= end || !between {
+        idx += 1;
+      } else {
+        break;
+      }
+    }
+
+    ranges.push((start, end));
+    idx += 1;
+  }
+
+  ranges
+}
+
+// "@@ -1,2 +1,3 @@ fn main() {"
+fn range_header(lines: &[FullLine], start: usize, end: usize) -> String {
+  let range = &lines[start..end];
+  let side = |is_side: fn(&FullLine) -> bool, lineno: fn(&FullLine) -> Option<u32>| {
+    let count = range.iter().filter(|line| is_side(line)).count();
+    let first = if count > 0 {
+      range.iter().filter_map(lineno).next()
+    } else {
+      // an empty side points at the line before it
+      lines[..start].iter().rev().filter_map(lineno).next()
+    };
+    format!("{},{}", first.unwrap_or(0), count)
+  };
+
+  let mut header = format!(
+    "@@ -{} +{} @@",
+    side(FullLine::is_old, |line| line.old_lineno),
+    side(FullLine::is_new, |line| line.new_lineno)
+  );
+
+  if let Some(function) = lines[..start].iter().rev().find(|line| line.is_function()) {
+    let function = String::from_utf8_lossy(&function.content);
+    let function: String = function.trim_end().chars().take(80).collect();
+    header.push(' ');
+    header.push_str(&function);
+  }
+
+  header
+}
+
+/// Cuts `diff` down to the changes plus the whole function around each of
+/// them, as well as `context` lines on either side. `diff` should have been
+/// generated with enough context to hold each file in a single hunk.
+pub fn function_context(diff: &Diff, context: u32) -> Result<Diff<'static>, Error> {
+  let mut buf = Vec::new();
+
+  for idx in 0..diff.deltas().len() {
+    let mut patch = match Patch::from_diff(diff, idx).with_context(|_| "couldn't generate patch")? {
+      Some(patch) => patch,
+      None => continue,
+    };
+
+    let text = patch.to_buf().with_context(|_| "couldn't format diff")?;
+    if patch.num_hunks() == 0 {
+      buf.extend_from_slice(&text);
+      continue;
+    }
+
+    // the file header is everything up to the first hunk
+    let mut header_len = 0;
+    for line in text.split_inclusive(|&c| c == b'\n') {
+      if line.starts_with(b"@@") {
+        break;
+      }
+      header_len += line.len();
+    }
+    buf.extend_from_slice(&text[..header_len]);
+
+    for hunk_idx in 0..patch.num_hunks() {
+      let (_, line_count) = patch
+        .hunk(hunk_idx)
+        .with_context(|_| "couldn't read hunk")?;
+
+      let mut lines = Vec::new();
+      for line_idx in 0..line_count {
+        let line = patch
+          .line_in_hunk(hunk_idx, line_idx)
+          .with_context(|_| "couldn't read line")?;
+        if let ' ' | '+' | '-' = line.origin() {
+          lines.push(FullLine {
+            origin: line.origin(),
+            content: line.content().to_vec(),
+            old_lineno: line.old_lineno(),
+            new_lineno: line.new_lineno(),
+          });
+        }
+      }
+
+      for (start, end) in function_ranges(&lines, context as usize) {
+        buf.extend_from_slice(range_header(&lines, start, end).as_bytes());
+        buf.push(b'\n');
+        for line in &lines[start..end] {
+          buf.push(line.origin as u8);
+          buf.extend_from_slice(&line.content);
+          if !line.content.ends_with(b"\n") {
+            buf.extend_from_slice(b"\n\\ No newline at end of file\n");
+          }
+        }
+      }
+    }
+  }
+
+  let diff = Diff::from_buffer(&buf).with_context(|_| "couldn't parse diff")?;
+  Ok(diff)
+}
@@ -0,0 +1,264 @@
+use super::FileSide;
+use colored::*;
+use failure::format_err;
+use failure::Error;
+use failure::ResultExt;
+use git2::Config;
+use git2::Delta;
+use git2::Diff;
+use git2::DiffFile;
+use git2::FileMode;
+use git2::Repository;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use tempfile::TempDir;
+
+// programs and arguments for tools that git knows about out of the box, so
+// they work without any difftool.<name>.cmd config. the program can still be
+// swapped out with difftool.<name>.path
+const KNOWN_TOOLS: &[(&str, &str, &str)] = &[
+  ("bc", "bcompare", r#""$LOCAL" "$REMOTE""#),
+  ("code", "code", r#"--wait --diff "$LOCAL" "$REMOTE""#),
+  ("diffuse", "diffuse", r#""$LOCAL" "$REMOTE""#),
+  ("kdiff3", "kdiff3", r#""$LOCAL" "$REMOTE""#),
+  ("kompare", "kompare", r#""$LOCAL" "$REMOTE""#),
+  ("meld", "meld", r#""$LOCAL" "$REMOTE""#),
+  ("nvimdiff", "nvim", r#"-d "$LOCAL" "$REMOTE""#),
+  (
+    "opendiff",
+    "opendiff",
+    r#""$LOCAL" "$REMOTE" -merge "$MERGED""#,
+  ),
+  ("tkdiff", "tkdiff", r#""$LOCAL" "$REMOTE""#),
+  ("vimdiff", "vim", r#"-d "$LOCAL" "$REMOTE""#),
+];
+
+// quotes `word` for sh, so configured paths with spaces in them still work
+fn shell_quote(word: &str) -> String {
+  format!("'{}'", word.replace('\'', r#"'\''"#))
+}
+
+/// Picks the tool named by `diff.tool` when none was given explicitly.
+pub fn configured_tool(config: &Config) -> Result<String, Error> {
+  let tool = config
+    .get_string("diff.tool")
+    .with_context(|_| "no tool given and diff.tool isn't set")?;
+  Ok(tool)
+}
+
+/// Looks up the shell command for `tool`, following git: `difftool.<tool>.cmd`
+/// first, then the built-in list, and finally the tool name itself as a
+/// program taking the two files as arguments.
+pub fn tool_command(config: &Config, tool: &str) -> String {
+  if let Ok(command) = config.get_string(&format!("difftool.{}.cmd", tool)) {
+    return command;
+  }
+
+  let path = config.get_string(&format!("difftool.{}.path", tool)).ok();
+
+  let (program, args) = match KNOWN_TOOLS.iter().find(|(name, _, _)| *name == tool) {
+    Some((_, program, args)) => (path.unwrap_or_else(|| program.to_string()), *args),
+    None => (
+      path.unwrap_or_else(|| tool.to_string()),
+      r#""$LOCAL" "$REMOTE""#,
+    ),
+  };
+
+  format!("{} {}", shell_quote(&program), args)
+}
+
+/// A scratch directory holding the old and new sides of a diff, removed once
+/// the tool is done with it. It's created with a random name that nobody else
+/// could have claimed first, and is only readable by the current user.
+struct Scratch {
+  dir: Option<TempDir>,
+}
+
+impl Scratch {
+  fn new() -> Result<Scratch, Error> {
+    let dir = tempfile::Builder::new()
+      .prefix("milk-difftool-")
+      .permissions(fs::Permissions::from_mode(0o700))
+      .tempdir()
+      .with_context(|_| "couldn't create temporary directory")?;
+    Ok(Scratch { dir: Some(dir) })
+  }
+
+  fn root(&self) -> &Path {
+    self.dir.as_ref().unwrap().path()
+  }
+
+  fn old_dir(&self) -> PathBuf {
+    self.root().join("old")
+  }
+
+  fn new_dir(&self) -> PathBuf {
+    self.root().join("new")
+  }
+}
+
+impl Drop for Scratch {
+  fn drop(&mut self) {
+    if let Some(dir) = self.dir.take() {
+      let root = dir.path().to_path_buf();
+      if dir.close().is_err() {
+        eprintln!("WARNING: Unable to delete {} after use", root.display());
+      }
+    }
+  }
+}
+
+/// Writes one side of a delta to `dest`. Files that don't exist on this side
+/// come out empty, symlinks as their target and submodules as the commit they
+/// point to, the same way git shows them.
+fn materialize(repo: &Repository, file: &DiffFile, dest: &Path) -> Result<(), Error> {
+  if let Some(parent) = dest.parent() {
+    fs::create_dir_all(parent).with_context(|_| "couldn't create temporary directory")?;
+  }
+
+  let contents = if !file.exists() {
+    Vec::new()
+  } else if file.mode() == FileMode::Commit {
+    format!("Subproject commit {}\n", file.id()).into_bytes()
+  } else if let Ok(blob) = repo.find_blob(file.id()) {
+    blob.content().to_vec()
+  } else {
+    // the work tree side usually isn't in the object database
+    let workdir = repo
+      .workdir()
+      .ok_or_else(|| failure::err_msg("repository is bare"))?;
+    let path = workdir.join(file.path().unwrap_or_else(|| Path::new("")));
+
+    if file.mode() == FileMode::Link {
+      let target = fs::read_link(&path).with_context(|_| "couldn't read symlink")?;
+      target.as_os_str().as_bytes().to_vec()
+    } else {
+      fs::read(&path).with_context(|_| format!("couldn't read {}", path.display()))?
+    }
+  };
+
+  fs::write(dest, contents).with_context(|_| "couldn't write temporary file")?;
+  Ok(())
+}
+
+fn run_tool(command: &str, local: &Path, remote: &Path, merged: &Path) -> Result<(), Error> {
+  let status = process::Command::new("sh")
+    .arg("-c")
+    .arg(command)
+    .env("LOCAL", local)
+    .env("REMOTE", remote)
+    .env("MERGED", merged)
+    .env("BASE", merged)
+    .status()
+    .with_context(|_| "couldn't run diff tool")?;
+
+  // sh reports a tool it couldn't find or execute with these codes
+  if let Some(126) | Some(127) = status.code() {
+    return Err(format_err!("couldn't run diff tool: {}", command));
+  }
+
+  // plenty of tools exit non-zero just because the files differ, so like git
+  // difftool, that isn't a reason to skip the rest of the files
+  if !status.success() {
+    eprintln!("{}: diff tool exited with {}", "warning".red(), status);
+  }
+
+  Ok(())
+}
+
+/// Opens every changed file in `diff` with the external `command`, one file
+/// at a time. With `dir_diff`, both sides are written out as whole directory
+/// trees and the tool is run once on the pair.
+pub fn run(repo: &Repository, diff: &Diff, command: &str, dir_diff: bool) -> Result<(), Error> {
+  let deltas: Vec<_> = diff
+    .deltas()
+    .filter(|delta| delta.status() != Delta::Unmodified)
+    .collect();
+
+  if deltas.is_empty() {
+    return Ok(());
+  }
+
+  let scratch = Scratch::new()?;
+
+  if dir_diff {
+    // added and deleted files are simply missing from one of the directories
+    for delta in &deltas {
+      for (file, dir) in &[
+        (delta.old_file(), scratch.old_dir()),
+        (delta.new_file(), scratch.new_dir()),
+      ] {
+        if let (true, Some(path)) = (file.exists(), file.path()) {
+          materialize(repo, file, &dir.join(path))?;
+        }
+      }
+    }
+
+    // tools still expect both directories even if one side is empty
+    fs::create_dir_all(scratch.old_dir())
+      .with_context(|_| "couldn't create temporary directory")?;
+    fs::create_dir_all(scratch.new_dir())
+      .with_context(|_| "couldn't create temporary directory")?;
+
+    return run_tool(
+      command,
+      &scratch.old_dir(),
+      &scratch.new_dir(),
+      &scratch.new_dir(),
+    );
+  }
+
+  for (i, delta) in deltas.iter().enumerate() {
+    let path = delta
+      .new_file()
+      .path()
+      .or_else(|| delta.old_file().path())
+      .unwrap_or_else(|| Path::new("[unknown]"));
+
+    let local = scratch
+      .old_dir()
+      .join(delta.old_file().path().unwrap_or(path));
+    let remote = scratch.new_dir().join(path);
+    materialize(repo, &delta.old_file(), &local)?;
+    materialize(repo, &delta.new_file(), &remote)?;
+
+    println!(
+      "{} ({}/{}): {}",
+      "viewing".cyan(),
+      i + 1,
+      deltas.len(),
+      path.display()
+    );
+    run_tool(command, &local, &remote, path)?;
+  }
+
+  Ok(())
+}
+
+/// Opens a pair of single files with the external `command`.
+pub fn run_files(old: &FileSide, new: &FileSide, command: &str) -> Result<(), Error> {
+  let scratch = Scratch::new()?;
+
+  // keep the file names so tools can still pick a syntax from them
+  let file_name = |side: &FileSide| {
+    side
+      .path()
+      .file_name()
+      .map(PathBuf::from)
+      .unwrap_or_else(|| PathBuf::from("file"))
+  };
+  let local = scratch.old_dir().join(file_name(old));
+  let remote = scratch.new_dir().join(file_name(new));
+
+  for (side, dest) in &[(old, &local), (new, &remote)] {
+    fs::create_dir_all(dest.parent().unwrap())
+      .with_context(|_| "couldn't create temporary directory")?;
+    fs::write(dest, side.content()).with_context(|_| "couldn't write temporary file")?;
+  }
+
+  run_tool(command, &local, &remote, new.path())
+}
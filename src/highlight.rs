@@ -0,0 +1,82 @@
+use colored::*;
+use failure::format_err;
+use failure::Error;
+use std::path::Path;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::Theme;
+use syntect::highlighting::ThemeSet;
+use syntect::parsing::SyntaxReference;
+use syntect::parsing::SyntaxSet;
+use syntect::util::as_24_bit_terminal_escaped;
+use syntect::util::LinesWithEndings;
+
+pub const DEFAULT_THEME: &str = "base16-ocean.dark";
+
+pub fn theme_names() -> Vec<String> {
+  ThemeSet::load_defaults().themes.keys().cloned().collect()
+}
+
+fn find_theme(name: &str) -> Result<Theme, Error> {
+  let mut themes = ThemeSet::load_defaults().themes;
+  themes.remove(name).ok_or_else(|| {
+    format_err!(
+      "unknown theme `{}`; available themes are: {}",
+      name,
+      theme_names().join(", ")
+    )
+  })
+}
+
+fn find_syntax<'a>(
+  syntaxes: &'a SyntaxSet,
+  path: Option<&Path>,
+  text: &str,
+) -> &'a SyntaxReference {
+  let by_name = path.and_then(|path| {
+    // extensionless files like Makefile are registered under their full name
+    let extension = path.extension().or_else(|| path.file_name())?;
+    syntaxes.find_syntax_by_extension(extension.to_str()?)
+  });
+
+  by_name
+    .or_else(|| syntaxes.find_syntax_by_first_line(text.lines().next()?))
+    .unwrap_or_else(|| syntaxes.find_syntax_plain_text())
+}
+
+/// Prints `content` with syntax highlighting and a line number gutter. The
+/// language is guessed from `path` when there is one, and from the first line
+/// otherwise. Content that isn't UTF-8 is printed as-is.
+pub fn print_highlighted(
+  content: &[u8],
+  path: Option<&Path>,
+  theme_name: &str,
+) -> Result<(), Error> {
+  let text = match std::str::from_utf8(content) {
+    Ok(text) => text,
+    Err(_) => {
+      print!("{}", String::from_utf8_lossy(content));
+      return Ok(());
+    }
+  };
+
+  let syntaxes = SyntaxSet::load_defaults_newlines();
+  let theme = find_theme(theme_name)?;
+  let syntax = find_syntax(&syntaxes, path, text);
+  let mut highlighter = HighlightLines::new(syntax, &theme);
+
+  let line_count = text.lines().count();
+  let gutter_width = line_count.to_string().len();
+
+  for (number, line) in LinesWithEndings::from(text).enumerate() {
+    let ranges = highlighter.highlight(line, &syntaxes);
+    let escaped = as_24_bit_terminal_escaped(&ranges, false);
+    let gutter = format!("{:>width$} │", number + 1, width = gutter_width);
+
+    print!("{} {}\x1b[0m", gutter.bright_black(), escaped);
+    if !line.ends_with('\n') {
+      println!();
+    }
+  }
+
+  Ok(())
+}
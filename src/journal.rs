@@ -0,0 +1,288 @@
+use chrono::offset::Local;
+use chrono::offset::TimeZone;
+use chrono::DateTime;
+use failure::format_err;
+use failure::Error;
+use failure::ResultExt;
+use git2::Oid;
+use git2::Repository;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::PathBuf;
+
+pub struct CleanedFile {
+  pub path: String,
+  pub oid: Oid,
+  pub mode: i32,
+}
+
+pub struct CleanRecord {
+  pub id: usize,
+  pub time: DateTime<Local>,
+  pub files: Vec<CleanedFile>,
+}
+
+/// Everything milk persists about a repository lives in `.git/milk`, so it
+/// travels with the repository and stays out of the working tree.
+pub fn milk_dir(repo: &Repository) -> PathBuf {
+  repo.path().join("milk")
+}
+
+// only made once there's something to write, so reading never leaves it behind
+fn create_milk_dir(repo: &Repository) -> Result<(), Error> {
+  fs::create_dir_all(milk_dir(repo)).with_context(|_| "couldn't create milk directory")?;
+  Ok(())
+}
+
+fn clean_log_path(repo: &Repository) -> PathBuf {
+  milk_dir(repo).join("cleans")
+}
+
+/// Each cleaned file is one line of `id time mode oid path`, separated by
+/// tabs. The path comes last so that it may contain tabs itself.
+pub fn read_cleans(repo: &Repository) -> Result<Vec<CleanRecord>, Error> {
+  let log_path = clean_log_path(repo);
+  if !log_path.exists() {
+    return Ok(Vec::new());
+  }
+
+  let handle = File::open(&log_path).with_context(|_| "couldn't open clean log")?;
+  let mut records: Vec<CleanRecord> = Vec::new();
+
+  for line in BufReader::new(handle).lines() {
+    let line = line.with_context(|_| "couldn't read clean log")?;
+    if line.is_empty() {
+      continue;
+    }
+
+    let fields: Vec<_> = line.splitn(5, '\t').collect();
+    if fields.len() != 5 {
+      return Err(format_err!("malformed clean log line: {}", line));
+    }
+
+    let id: usize = fields[0].parse()?;
+    let time = Local
+      .timestamp_opt(fields[1].parse()?, 0)
+      .single()
+      .ok_or_else(|| format_err!("invalid time in clean log: {}", fields[1]))?;
+    let mode = i32::from_str_radix(fields[2], 8)?;
+    let oid = Oid::from_str(fields[3])?;
+    let file = CleanedFile {
+      path: fields[4].to_string(),
+      oid,
+      mode,
+    };
+
+    match records.last_mut() {
+      Some(record) if record.id == id => record.files.push(file),
+      _ => records.push(CleanRecord {
+        id,
+        time,
+        files: vec![file],
+      }),
+    }
+  }
+
+  Ok(records)
+}
+
+pub fn find_clean(repo: &Repository, id: usize) -> Result<CleanRecord, Error> {
+  read_cleans(repo)?
+    .into_iter()
+    .find(|record| record.id == id)
+    .ok_or_else(|| format_err!("no clean with id {}", id))
+}
+
+pub fn record_clean(repo: &Repository, files: &[CleanedFile]) -> Result<usize, Error> {
+  let id = read_cleans(repo)?
+    .last()
+    .map(|record| record.id + 1)
+    .unwrap_or(1);
+  let time = Local::now().timestamp();
+
+  create_milk_dir(repo)?;
+  let mut log = OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(clean_log_path(repo))
+    .with_context(|_| "couldn't open clean log")?;
+
+  for file in files {
+    writeln!(
+      log,
+      "{}\t{}\t{:o}\t{}\t{}",
+      id, time, file.mode, file.oid, file.path
+    )
+    .with_context(|_| "couldn't write clean log")?;
+  }
+
+  Ok(id)
+}
+
+pub struct RefChange {
+  pub name: String,
+  pub old: Option<Oid>,
+  pub new: Option<Oid>,
+}
+
+pub struct Operation {
+  pub id: usize,
+  pub time: DateTime<Local>,
+  pub description: String,
+  pub refs: Vec<RefChange>,
+  pub index: Option<(Oid, Oid)>,
+  pub clean: Option<usize>,
+}
+
+/// Captures the state of everything a command is about to touch, so that the
+/// state afterwards can be recorded next to it in the operation log.
+pub struct PendingOperation {
+  description: String,
+  refs: Vec<(String, Option<Oid>)>,
+  index: Option<Oid>,
+  track_index: bool,
+  clean: Option<usize>,
+}
+
+fn oplog_path(repo: &Repository) -> PathBuf {
+  milk_dir(repo).join("oplog")
+}
+
+fn format_optional_oid(oid: Option<Oid>) -> String {
+  match oid {
+    Some(oid) => oid.to_string(),
+    None => "-".to_string(),
+  }
+}
+
+fn parse_optional_oid(s: &str) -> Result<Option<Oid>, Error> {
+  match s {
+    "-" => Ok(None),
+    _ => Ok(Some(Oid::from_str(s)?)),
+  }
+}
+
+/// The index is snapshotted as a tree, which drops conflict and stat
+/// information but is enough to put staged content back where it was.
+fn index_tree(repo: &Repository) -> Option<Oid> {
+  repo.index().and_then(|mut index| index.write_tree()).ok()
+}
+
+impl PendingOperation {
+  pub fn new(description: &str) -> PendingOperation {
+    PendingOperation {
+      description: description.trim().to_string(),
+      refs: Vec::new(),
+      index: None,
+      track_index: false,
+      clean: None,
+    }
+  }
+
+  pub fn track_ref(&mut self, repo: &Repository, name: &str) {
+    let oid = repo.refname_to_id(name).ok();
+    self.refs.push((name.to_string(), oid));
+  }
+
+  pub fn track_index(&mut self, repo: &Repository) {
+    self.track_index = true;
+    self.index = index_tree(repo);
+  }
+
+  pub fn set_clean(&mut self, id: usize) {
+    self.clean = Some(id);
+  }
+
+  pub fn record(self, repo: &Repository) -> Result<usize, Error> {
+    let id = read_operations(repo)?
+      .last()
+      .map(|op| op.id + 1)
+      .unwrap_or(1);
+    let time = Local::now().timestamp();
+
+    create_milk_dir(repo)?;
+    let mut log = OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(oplog_path(repo))
+      .with_context(|_| "couldn't open operation log")?;
+
+    writeln!(log, "op\t{}\t{}\t{}", id, time, self.description)
+      .with_context(|_| "couldn't write operation log")?;
+
+    for (name, old) in &self.refs {
+      let new = repo.refname_to_id(name).ok();
+      writeln!(
+        log,
+        "ref\t{}\t{}\t{}",
+        format_optional_oid(*old),
+        format_optional_oid(new),
+        name
+      )
+      .with_context(|_| "couldn't write operation log")?;
+    }
+
+    if self.track_index {
+      if let (Some(old), Some(new)) = (self.index, index_tree(repo)) {
+        writeln!(log, "index\t{}\t{}", old, new)
+          .with_context(|_| "couldn't write operation log")?;
+      }
+    }
+
+    if let Some(clean) = self.clean {
+      writeln!(log, "clean\t{}", clean).with_context(|_| "couldn't write operation log")?;
+    }
+
+    Ok(id)
+  }
+}
+
+/// Operations are written as an `op` line followed by `ref`, `index` and
+/// `clean` lines describing what the operation changed, all tab-separated.
+pub fn read_operations(repo: &Repository) -> Result<Vec<Operation>, Error> {
+  let log_path = oplog_path(repo);
+  if !log_path.exists() {
+    return Ok(Vec::new());
+  }
+
+  let handle = File::open(&log_path).with_context(|_| "couldn't open operation log")?;
+  let mut operations: Vec<Operation> = Vec::new();
+
+  for line in BufReader::new(handle).lines() {
+    let line = line.with_context(|_| "couldn't read operation log")?;
+    let fields: Vec<_> = line.splitn(4, '\t').collect();
+
+    match (fields[0], operations.last_mut()) {
+      ("", _) => continue,
+      ("op", _) if fields.len() == 4 => {
+        let time = Local
+          .timestamp_opt(fields[2].parse()?, 0)
+          .single()
+          .ok_or_else(|| format_err!("invalid time in operation log: {}", fields[2]))?;
+        operations.push(Operation {
+          id: fields[1].parse()?,
+          time,
+          description: fields[3].to_string(),
+          refs: Vec::new(),
+          index: None,
+          clean: None,
+        });
+      }
+      ("ref", Some(op)) if fields.len() == 4 => op.refs.push(RefChange {
+        name: fields[3].to_string(),
+        old: parse_optional_oid(fields[1])?,
+        new: parse_optional_oid(fields[2])?,
+      }),
+      ("index", Some(op)) if fields.len() == 3 => {
+        op.index = Some((Oid::from_str(fields[1])?, Oid::from_str(fields[2])?));
+      }
+      ("clean", Some(op)) if fields.len() == 2 => op.clean = Some(fields[1].parse()?),
+      _ => return Err(format_err!("malformed operation log line: {}", line)),
+    }
+  }
+
+  Ok(operations)
+}
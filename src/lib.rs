@@ -1,4 +1,5 @@
 use chrono::offset::FixedOffset;
+use chrono::offset::Local;
 use chrono::offset::TimeZone;
 use chrono::DateTime;
 use colored::*;
@@ -7,28 +8,52 @@ use failure::Error;
 use failure::ResultExt;
 use git2::Blob;
 use git2::Commit;
+use git2::Config;
 use git2::Diff;
+use git2::DiffFindOptions;
 use git2::DiffOptions;
+use git2::FileMode;
 use git2::Object;
 use git2::ObjectType;
 use git2::Oid;
+use git2::Patch;
 use git2::Repository;
 use git2::Status;
+use git2::StatusOptions;
+use git2::Submodule;
+use git2::SubmoduleIgnore;
+use git2::SubmoduleStatus;
 use git2::Tag;
 use git2::Time;
 use git2::Tree;
 use std::env;
+use std::ffi::OsStr;
+use std::fs;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io;
 use std::io::prelude::*;
 use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::symlink;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Component;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process;
 
+pub mod binary;
+pub mod branch;
 pub mod cli;
 pub mod cmd;
+pub mod diff;
+pub mod difftool;
+pub mod highlight;
+pub mod journal;
+pub mod ls;
+pub mod pager;
+pub mod status;
 
 pub enum RepoPath {
   Path(PathBuf),
@@ -40,22 +65,39 @@ pub trait MilkRepo {
   fn print_commit(&self, commit: &Commit);
   fn print_tree(&self, tree: &Tree);
   fn print_blob(&self, blob: &Blob);
+  fn print_blob_summary(&self, blob: &Blob);
   fn print_tag(&self, tag: &Tag);
   fn print_object(&self, object: &Object);
   fn highlight_named_oid(&self, name: &str, oid: Oid) -> String;
   fn get_short_id(&self, oid: Oid) -> String;
   fn find_from_refname<'repo>(&'repo self, name: &str) -> Result<Object<'repo>, Error>;
   fn find_from_name<'repo>(&'repo self, name: &str) -> Result<Object<'repo>, Error>;
+  fn find_from_label_path<'repo>(
+    &'repo self,
+    label: &str,
+    path: &Path,
+  ) -> Result<Object<'repo>, Error>;
   fn write_blob(&self, path: &Path) -> Result<Oid, Error>;
+  fn restore_blob(&self, oid: Oid, mode: i32, path: &Path) -> Result<(), Error>;
   fn name_to_tree<'repo>(&'repo self, s: &str) -> Result<Tree<'repo>, Error>;
   fn make_diff<'repo>(
     &'repo self,
     old_target: DiffTarget,
     new_target: DiffTarget,
+    settings: &DiffSettings,
+  ) -> Result<Diff<'repo>, Error>;
+  fn make_function_diff<'repo>(
+    &'repo self,
+    old_target: DiffTarget,
+    new_target: DiffTarget,
+    settings: &DiffSettings,
   ) -> Result<Diff<'repo>, Error>;
   fn canonicalize_path(&self, path: &Path) -> Result<RepoPath, Error>;
   fn ignore_string(&self, line: &str) -> Result<(), Error>;
   fn ignore_file(&self, path: &Path) -> Result<(), Error>;
+  fn open_main_worktree(&self) -> Result<Repository, Error>;
+  fn is_dirty(&self, include_untracked: bool) -> Result<bool, Error>;
+  fn get_submodule_summary(&self, submodule: &Submodule) -> Result<String, Error>;
 }
 
 impl MilkRepo for Repository {
@@ -122,6 +164,11 @@ impl MilkRepo for Repository {
   }
 
   fn print_blob(&self, blob: &Blob) {
+    if blob.is_binary() {
+      self.print_blob_summary(blob);
+      return;
+    }
+
     let mut stdout = io::stdout();
 
     // what happens on failure?
@@ -130,6 +177,19 @@ impl MilkRepo for Repository {
     }
   }
 
+  fn print_blob_summary(&self, blob: &Blob) {
+    let content = blob.content();
+    let mime_type = if blob.is_binary() {
+      binary::detect_mime_type(content)
+    } else {
+      "text/plain"
+    };
+
+    println!("{} {} bytes", "size".cyan(), content.len());
+    println!("{} {}", "type".cyan(), mime_type);
+    println!("{} {}", "sha".cyan(), blob.id().to_string().bright_black());
+  }
+
   fn print_tag(&self, tag: &Tag) {
     println!("{}", self.highlight_named_oid("target", tag.target_id()));
 
@@ -205,6 +265,10 @@ impl MilkRepo for Repository {
   }
 
   fn find_from_name<'repo>(&'repo self, name: &str) -> Result<Object<'repo>, Error> {
+    if let Some((label, path)) = split_label_path(name) {
+      return self.find_from_label_path(label, path);
+    }
+
     let mut iter = name.chars();
     let head = iter.next();
     let tail: String = iter.collect();
@@ -230,15 +294,90 @@ impl MilkRepo for Repository {
     }
   }
 
+  fn find_from_label_path<'repo>(
+    &'repo self,
+    label: &str,
+    path: &Path,
+  ) -> Result<Object<'repo>, Error> {
+    let tree = self
+      .name_to_tree(label)
+      .with_context(|_| "couldn't look up tree")?;
+
+    if path.as_os_str().is_empty() {
+      return Ok(tree.into_object());
+    }
+
+    let label = if label.is_empty() { "HEAD" } else { label };
+    let entry = tree
+      .get_path(path)
+      .with_context(|_| format!("{} does not exist in {}", path.display(), label))?;
+    let object = entry
+      .to_object(self)
+      .with_context(|_| "couldn't find tree entry object")?;
+    Ok(object)
+  }
+
   fn write_blob(&self, path: &Path) -> Result<Oid, Error> {
     let odb = self.odb().with_context(|_| "couldn't open ODB")?;
-    let mut handle = File::open(path)?;
     let mut bytes = Vec::new();
-    let _size = handle.read_to_end(&mut bytes)?;
+
+    // git stores symlinks as blobs containing the link target
+    if fs::symlink_metadata(path)?.file_type().is_symlink() {
+      let target = fs::read_link(path)?;
+      bytes.extend_from_slice(target.as_os_str().as_bytes());
+    } else {
+      let mut handle = File::open(path)?;
+      let _size = handle.read_to_end(&mut bytes)?;
+    }
+
     let oid = odb.write(ObjectType::Blob, &bytes)?;
     Ok(oid)
   }
 
+  fn restore_blob(&self, oid: Oid, mode: i32, path: &Path) -> Result<(), Error> {
+    let blob = self.find_blob(oid).with_context(|_| "couldn't find blob")?;
+
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent).with_context(|_| "couldn't create parent directory")?;
+    }
+
+    // clear out whatever is there first so that symlinks get replaced instead
+    // of written through, and so the new file is created with our mode
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+      if metadata.is_dir() {
+        return Err(format_err!("{} is a directory", path.display()));
+      }
+      fs::remove_file(path).with_context(|_| "couldn't remove existing file")?;
+    }
+
+    if mode == i32::from(FileMode::Link) {
+      let target = OsStr::from_bytes(blob.content());
+      symlink(target, path).with_context(|_| "couldn't create symlink")?;
+      return Ok(());
+    }
+
+    // like git, leave it to the umask to decide who else gets to read or run
+    // the file
+    let permissions = if mode == i32::from(FileMode::BlobExecutable) {
+      0o777
+    } else {
+      0o666
+    };
+    let mut file = OpenOptions::new()
+      .write(true)
+      .truncate(true)
+      .create(true)
+      .mode(permissions)
+      .open(path)
+      .with_context(|_| "couldn't open file for writing")?;
+
+    file
+      .write_all(blob.content())
+      .with_context(|_| "couldn't write to file")?;
+
+    Ok(())
+  }
+
   fn name_to_tree<'repo>(&'repo self, s: &str) -> Result<Tree<'repo>, Error> {
     let tree = self
       .find_from_name(s)
@@ -252,102 +391,27 @@ impl MilkRepo for Repository {
     &'repo self,
     old_target: DiffTarget,
     new_target: DiffTarget,
+    settings: &DiffSettings,
   ) -> Result<Diff<'repo>, Error> {
     let mut options = DiffOptions::new();
+    settings.apply(&mut options);
+    diff_with_options(self, old_target, new_target, settings, &mut options)
+  }
 
-    match (old_target, new_target) {
-      // tree..
-      (DiffTarget::Name(old), DiffTarget::WorkingTree) => {
-        let old_tree = self
-          .name_to_tree(old)
-          .with_context(|_| "couldn't look up old tree")?;
-
-        let diff = self
-          .diff_tree_to_workdir(Some(&old_tree), Some(&mut options))
-          .with_context(|_| "couldn't generate diff")?;
-        Ok(diff)
-      }
-
-      (DiffTarget::Name(old), DiffTarget::Name(new)) => {
-        let old_tree = self
-          .name_to_tree(old)
-          .with_context(|_| "couldn't look up old tree")?;
-        let new_tree = self
-          .name_to_tree(new)
-          .with_context(|_| "couldn't look up new tree")?;
-
-        let diff = self
-          .diff_tree_to_tree(Some(&old_tree), Some(&new_tree), Some(&mut options))
-          .with_context(|_| "couldn't generate diff")?;
-        Ok(diff)
-      }
-
-      (DiffTarget::Name(old), DiffTarget::Index) => {
-        let old_tree = self
-          .name_to_tree(old)
-          .with_context(|_| "couldn't look up old tree")?;
-        let index = self.index().with_context(|_| "couldn't read index")?;
-
-        let diff = self
-          .diff_tree_to_index(Some(&old_tree), Some(&index), Some(&mut options))
-          .with_context(|_| "couldn't generate diff")?;
-        Ok(diff)
-      }
-
-      // index..
-      (DiffTarget::Index, DiffTarget::WorkingTree) => {
-        let index = self.index().with_context(|_| "couldn't read index")?;
-        let diff = self
-          .diff_index_to_workdir(Some(&index), Some(&mut options))
-          .with_context(|_| "couldn't generate diff")?;
-
-        Ok(diff)
-      }
-
-      (DiffTarget::Index, DiffTarget::Name(new)) => {
-        let index = self.index().with_context(|_| "couldn't read index")?;
-        let new_tree = self
-          .name_to_tree(new)
-          .with_context(|_| "couldn't look up new tree")?;
-        options.reverse(true);
-
-        let diff = self
-          .diff_tree_to_index(Some(&new_tree), Some(&index), Some(&mut options))
-          .with_context(|_| "couldn't generate diff")?;
-        Ok(diff)
-      }
-
-      (DiffTarget::Index, DiffTarget::Index) => {
-        // FIXME why? it probably works...
-        Err(format_err!("Cannot diff between identical targets"))
-      }
+  fn make_function_diff<'repo>(
+    &'repo self,
+    old_target: DiffTarget,
+    new_target: DiffTarget,
+    settings: &DiffSettings,
+  ) -> Result<Diff<'repo>, Error> {
+    // libgit2 doesn't know about functions, so diff with every line included
+    // and cut the hunks down to the functions that changed afterwards
+    let mut options = DiffOptions::new();
+    settings.apply(&mut options);
+    options.context_lines(FULL_CONTEXT);
+    let full_diff = diff_with_options(self, old_target, new_target, settings, &mut options)?;
 
-      // working..
-      (DiffTarget::WorkingTree, DiffTarget::WorkingTree) => {
-        // FIXME why? it probably works...
-        Err(format_err!("Cannot diff between identical targets"))
-      }
-      (DiffTarget::WorkingTree, DiffTarget::Name(new)) => {
-        let new_tree = self
-          .name_to_tree(new)
-          .with_context(|_| "couldn't look up new tree")?;
-        options.reverse(true);
-
-        let diff = self
-          .diff_tree_to_workdir(Some(&new_tree), Some(&mut options))
-          .with_context(|_| "couldn't generate diff")?;
-        Ok(diff)
-      }
-      (DiffTarget::WorkingTree, DiffTarget::Index) => {
-        let index = self.index().with_context(|_| "couldn't read index")?;
-        options.reverse(true);
-        let diff = self
-          .diff_index_to_workdir(Some(&index), Some(&mut options))
-          .with_context(|_| "couldn't generate diff")?;
-
-        Ok(diff)
-      }
-    }
+    diff::function_context(&full_diff, settings.context_lines.unwrap_or(3))
   }
 
   fn canonicalize_path(&self, path: &Path) -> Result<RepoPath, Error> {
@@ -422,8 +486,74 @@ impl MilkRepo for Repository {
 
     self.ignore_string(final_filepath)
   }
+
+  fn open_main_worktree(&self) -> Result<Repository, Error> {
+    // a linked worktree's git directory lives under the main repository's
+    // git directory, and its `commondir` file points back up to it
+    let mut common_dir = self.path().to_path_buf();
+    if self.is_worktree() {
+      let mut handle = File::open(self.path().join("commondir"))
+        .with_context(|_| "couldn't open worktree commondir")?;
+      let mut contents = String::new();
+      handle
+        .read_to_string(&mut contents)
+        .with_context(|_| "couldn't read worktree commondir")?;
+      common_dir.push(contents.trim());
+    }
+
+    let repo =
+      Repository::open(common_dir).with_context(|_| "couldn't open main worktree repository")?;
+    Ok(repo)
+  }
+
+  fn is_dirty(&self, include_untracked: bool) -> Result<bool, Error> {
+    let mut status_opts = StatusOptions::new();
+    status_opts.include_untracked(include_untracked);
+    status_opts.include_ignored(false);
+
+    let statuses = self
+      .statuses(Some(&mut status_opts))
+      .with_context(|_| "couldn't open status")?;
+
+    Ok(!statuses.is_empty())
+  }
+
+  fn get_submodule_summary(&self, submodule: &Submodule) -> Result<String, Error> {
+    let name = submodule
+      .name()
+      .ok_or_else(|| failure::err_msg("submodule name is not utf-8"))?;
+    let status = self
+      .submodule_status(name, SubmoduleIgnore::Unspecified)
+      .with_context(|_| "couldn't read submodule status")?;
+
+    // the commits live in the submodule's ODB, not ours
+    let sub_repo = submodule.open().ok();
+    let short_id = |oid: Oid| match &sub_repo {
+      Some(sub_repo) => sub_repo.get_short_id(oid),
+      None => oid.to_string(),
+    };
+
+    let mut summary = match submodule.index_id() {
+      Some(oid) => format!("{}", short_id(oid).bright_black()),
+      None => format!("{}", "[not recorded]".bright_black()),
+    };
+
+    if let (Some(recorded), Some(checked_out)) = (submodule.index_id(), submodule.workdir_id()) {
+      if recorded != checked_out {
+        summary.push_str(&format!(" -> {}", short_id(checked_out).yellow()));
+      }
+    }
+
+    let status_string = get_submodule_status_string(status);
+    if !status_string.is_empty() {
+      summary.push_str(&format!(" {}", status_string));
+    }
+
+    Ok(summary)
+  }
 }
 
+#[derive(Clone, Copy)]
 pub enum DiffTarget<'a> {
   WorkingTree,
   Index,
@@ -440,6 +570,334 @@ impl<'a> DiffTarget<'a> {
   }
 }
 
+/// One side of a diff between two single files.
+pub enum FileSide<'repo> {
+  Blob(Blob<'repo>, PathBuf),
+  Buffer(Vec<u8>, PathBuf),
+}
+
+impl<'repo> FileSide<'repo> {
+  pub fn path(&self) -> &Path {
+    match self {
+      FileSide::Blob(_, path) | FileSide::Buffer(_, path) => path,
+    }
+  }
+
+  pub fn content(&self) -> &[u8] {
+    match self {
+      FileSide::Blob(blob, _) => blob.content(),
+      FileSide::Buffer(buffer, _) => buffer,
+    }
+  }
+}
+
+// libgit2 refuses absolute paths in patch headers and `../` makes for a
+// confusing one, so files outside of the work tree go by their file name
+fn file_display_path(repo: &Repository, path: &Path) -> PathBuf {
+  let escapes = path.is_absolute()
+    || path
+      .components()
+      .any(|component| component == Component::ParentDir);
+  if !escapes {
+    return path.to_path_buf();
+  }
+
+  let inside_workdir = repo.workdir().and_then(|workdir| {
+    let full = fs::canonicalize(path).ok()?;
+    let workdir = fs::canonicalize(workdir).ok()?;
+    full.strip_prefix(workdir).ok().map(Path::to_path_buf)
+  });
+
+  inside_workdir
+    .or_else(|| path.file_name().map(PathBuf::from))
+    .unwrap_or_else(|| path.to_path_buf())
+}
+
+/// Resolves a diff target that names a single file: a label or `label:path`
+/// expression that points at a blob, or a file on disk that isn't a label.
+pub fn file_side<'repo>(
+  repo: &'repo Repository,
+  target: DiffTarget,
+) -> Result<Option<FileSide<'repo>>, Error> {
+  let name = match target {
+    DiffTarget::Name(name) => name,
+    _ => return Ok(None),
+  };
+
+  let display_path = match split_label_path(name) {
+    Some((_, path)) => path.to_path_buf(),
+    None => file_display_path(repo, Path::new(name)),
+  };
+
+  match repo.find_from_name(name) {
+    Ok(object) => Ok(
+      object
+        .into_blob()
+        .ok()
+        .map(|blob| FileSide::Blob(blob, display_path)),
+    ),
+
+    Err(_) if split_label_path(name).is_none() && Path::new(name).is_file() => {
+      let contents = fs::read(name).with_context(|_| format!("couldn't read {}", name))?;
+      Ok(Some(FileSide::Buffer(contents, display_path)))
+    }
+
+    // let the tree lookup report what went wrong
+    Err(_) => Ok(None),
+  }
+}
+
+// libgit2 only hands back a patch for blob and buffer diffs, so turn it back
+// into a Diff to reuse all of the usual printing
+fn diff_file_sides<'repo>(
+  old: &FileSide,
+  new: &FileSide,
+  options: &mut DiffOptions,
+) -> Result<Diff<'repo>, Error> {
+  let old_path = Some(old.path());
+  let new_path = Some(new.path());
+
+  let mut patch = match (old, new) {
+    (FileSide::Blob(old_blob, _), FileSide::Blob(new_blob, _)) => {
+      Patch::from_blobs(old_blob, old_path, new_blob, new_path, Some(options))
+    }
+    (FileSide::Blob(old_blob, _), _) => {
+      Patch::from_blob_and_buffer(old_blob, old_path, new.content(), new_path, Some(options))
+    }
+    _ => Patch::from_buffers(
+      old.content(),
+      old_path,
+      new.content(),
+      new_path,
+      Some(options),
+    ),
+  }
+  .with_context(|_| "couldn't generate diff")?;
+
+  let buf = patch.to_buf().with_context(|_| "couldn't format diff")?;
+  let diff = Diff::from_buffer(&buf).with_context(|_| "couldn't parse diff")?;
+  Ok(diff)
+}
+
+fn diff_with_options<'repo>(
+  repo: &'repo Repository,
+  old_target: DiffTarget,
+  new_target: DiffTarget,
+  settings: &DiffSettings,
+  options: &mut DiffOptions,
+) -> Result<Diff<'repo>, Error> {
+  // single files get compared on their own, without any trees involved
+  match (file_side(repo, old_target)?, file_side(repo, new_target)?) {
+    (Some(old), Some(new)) => return diff_file_sides(&old, &new, options),
+    (None, None) => {}
+    _ => {
+      return Err(failure::err_msg(
+        "can't diff a single file against a whole tree",
+      ))
+    }
+  }
+
+  let mut diff = diff_targets(repo, old_target, new_target, options)?;
+  settings.renames.detect(&mut diff)?;
+
+  Ok(diff)
+}
+
+fn diff_targets<'repo>(
+  repo: &'repo Repository,
+  old_target: DiffTarget,
+  new_target: DiffTarget,
+  options: &mut DiffOptions,
+) -> Result<Diff<'repo>, Error> {
+  match (old_target, new_target) {
+    // tree..
+    (DiffTarget::Name(old), DiffTarget::WorkingTree) => {
+      let old_tree = repo
+        .name_to_tree(old)
+        .with_context(|_| "couldn't look up old tree")?;
+
+      let diff = repo
+        .diff_tree_to_workdir(Some(&old_tree), Some(options))
+        .with_context(|_| "couldn't generate diff")?;
+      Ok(diff)
+    }
+
+    (DiffTarget::Name(old), DiffTarget::Name(new)) => {
+      let old_tree = repo
+        .name_to_tree(old)
+        .with_context(|_| "couldn't look up old tree")?;
+      let new_tree = repo
+        .name_to_tree(new)
+        .with_context(|_| "couldn't look up new tree")?;
+
+      let diff = repo
+        .diff_tree_to_tree(Some(&old_tree), Some(&new_tree), Some(options))
+        .with_context(|_| "couldn't generate diff")?;
+      Ok(diff)
+    }
+
+    (DiffTarget::Name(old), DiffTarget::Index) => {
+      let old_tree = repo
+        .name_to_tree(old)
+        .with_context(|_| "couldn't look up old tree")?;
+      let index = repo.index().with_context(|_| "couldn't read index")?;
+
+      let diff = repo
+        .diff_tree_to_index(Some(&old_tree), Some(&index), Some(options))
+        .with_context(|_| "couldn't generate diff")?;
+      Ok(diff)
+    }
+
+    // index..
+    (DiffTarget::Index, DiffTarget::WorkingTree) => {
+      let index = repo.index().with_context(|_| "couldn't read index")?;
+      let diff = repo
+        .diff_index_to_workdir(Some(&index), Some(options))
+        .with_context(|_| "couldn't generate diff")?;
+
+      Ok(diff)
+    }
+
+    (DiffTarget::Index, DiffTarget::Name(new)) => {
+      let index = repo.index().with_context(|_| "couldn't read index")?;
+      let new_tree = repo
+        .name_to_tree(new)
+        .with_context(|_| "couldn't look up new tree")?;
+      options.reverse(true);
+
+      let diff = repo
+        .diff_tree_to_index(Some(&new_tree), Some(&index), Some(options))
+        .with_context(|_| "couldn't generate diff")?;
+      Ok(diff)
+    }
+
+    (DiffTarget::Index, DiffTarget::Index) => {
+      // FIXME why? it probably works...
+      Err(format_err!("Cannot diff between identical targets"))
+    }
+
+    // working..
+    (DiffTarget::WorkingTree, DiffTarget::WorkingTree) => {
+      // FIXME why? it probably works...
+      Err(format_err!("Cannot diff between identical targets"))
+    }
+    (DiffTarget::WorkingTree, DiffTarget::Name(new)) => {
+      let new_tree = repo
+        .name_to_tree(new)
+        .with_context(|_| "couldn't look up new tree")?;
+      options.reverse(true);
+
+      let diff = repo
+        .diff_tree_to_workdir(Some(&new_tree), Some(options))
+        .with_context(|_| "couldn't generate diff")?;
+      Ok(diff)
+    }
+    (DiffTarget::WorkingTree, DiffTarget::Index) => {
+      let index = repo.index().with_context(|_| "couldn't read index")?;
+      options.reverse(true);
+      let diff = repo
+        .diff_index_to_workdir(Some(&index), Some(options))
+        .with_context(|_| "couldn't generate diff")?;
+
+      Ok(diff)
+    }
+  }
+}
+
+/// Controls how deleted and added files get paired up as renames or copies.
+#[derive(Default)]
+pub struct RenameDetection {
+  pub renames: bool,
+  pub copies: bool,
+  pub threshold: Option<u16>,
+  pub limit: Option<usize>,
+}
+
+impl RenameDetection {
+  /// Reads the defaults from `diff.renames` and `diff.renameLimit`, the same
+  /// way git does: renames are on unless disabled, and copies are opt-in.
+  pub fn from_config(config: &Config) -> RenameDetection {
+    let setting = config
+      .get_string("diff.renames")
+      .unwrap_or_else(|_| "true".to_string())
+      .to_lowercase();
+    let copies = setting == "copies" || setting == "copy";
+    let renames = copies || !["false", "no", "off", "0"].contains(&setting.as_str());
+    let limit = config
+      .get_i64("diff.renameLimit")
+      .ok()
+      .filter(|&limit| limit > 0)
+      .map(|limit| limit as usize);
+
+    RenameDetection {
+      renames,
+      copies,
+      threshold: None,
+      limit,
+    }
+  }
+
+  pub fn find_options(&self) -> Option<DiffFindOptions> {
+    if !self.renames && !self.copies {
+      return None;
+    }
+
+    let mut options = DiffFindOptions::new();
+    options.renames(true);
+    options.copies(self.copies);
+
+    if let Some(threshold) = self.threshold {
+      options.rename_threshold(threshold);
+      options.copy_threshold(threshold);
+    }
+
+    if let Some(limit) = self.limit {
+      options.rename_limit(limit);
+    }
+
+    Some(options)
+  }
+
+  fn detect(&self, diff: &mut Diff) -> Result<(), Error> {
+    if let Some(mut find_options) = self.find_options() {
+      diff
+        .find_similar(Some(&mut find_options))
+        .with_context(|_| "couldn't detect renames")?;
+    }
+    Ok(())
+  }
+}
+
+// enough context lines to cover any file in one hunk
+const FULL_CONTEXT: u32 = i32::MAX as u32;
+
+/// Everything besides the two targets that shapes the diff `make_diff` returns.
+#[derive(Default)]
+pub struct DiffSettings {
+  pub pathspecs: Vec<String>,
+  pub ignore_whitespace: bool,
+  pub ignore_whitespace_change: bool,
+  pub ignore_blank_lines: bool,
+  pub context_lines: Option<u32>,
+  pub renames: RenameDetection,
+}
+
+impl DiffSettings {
+  fn apply(&self, options: &mut DiffOptions) {
+    for pathspec in &self.pathspecs {
+      options.pathspec(pathspec);
+    }
+
+    options.ignore_whitespace(self.ignore_whitespace);
+    options.ignore_whitespace_change(self.ignore_whitespace_change);
+    options.ignore_blank_lines(self.ignore_blank_lines);
+
+    if let Some(context_lines) = self.context_lines {
+      options.context_lines(context_lines);
+    }
+  }
+}
+
 pub fn git_to_chrono(sig: &Time) -> DateTime<FixedOffset> {
   let timestamp = sig.seconds();
   let offset_sec = sig.offset_minutes() * 60;
@@ -447,6 +905,35 @@ pub fn git_to_chrono(sig: &Time) -> DateTime<FixedOffset> {
   fixed_offset.timestamp(timestamp, 0)
 }
 
+/// Describes how long ago `time` was, roughly, the way git does: "3 hours ago",
+/// "2 weeks ago" and so on.
+pub fn relative_time(time: &Time) -> String {
+  let seconds = (Local::now().timestamp() - time.seconds()).max(0);
+
+  let minutes = (seconds + 30) / 60;
+  let hours = (minutes + 30) / 60;
+  let days = (hours + 12) / 24;
+
+  let (count, unit) = if seconds < 90 {
+    (seconds, "second")
+  } else if minutes < 90 {
+    (minutes, "minute")
+  } else if hours < 36 {
+    (hours, "hour")
+  } else if days < 14 {
+    (days, "day")
+  } else if days < 70 {
+    ((days + 3) / 7, "week")
+  } else if days < 365 {
+    ((days + 15) / 30, "month")
+  } else {
+    ((days + 183) / 365, "year")
+  };
+
+  let plural = if count == 1 { "" } else { "s" };
+  format!("{} {}{} ago", count, unit, plural)
+}
+
 /// `options` will be interspersed with forward slashes and presented to the
 /// user. For example, with `options = "Yn?"`, the user will be prompted for
 /// `[Y/n/?]`
@@ -517,6 +1004,28 @@ pub fn editor(path: &Path, contents: &str) -> Result<String, Error> {
   Ok(contents)
 }
 
+/// Splits a `label:path` expression into its label and path. The label may be
+/// empty, which refers to HEAD like any other empty label.
+pub fn split_label_path(name: &str) -> Option<(&str, &Path)> {
+  let split = name.find(':')?;
+  Some((&name[..split], Path::new(&name[split + 1..])))
+}
+
+/// Returns the git file mode that a working tree file would be recorded with.
+pub fn get_file_mode(path: &Path) -> Result<i32, Error> {
+  let metadata = fs::symlink_metadata(path)?;
+
+  let mode = if metadata.file_type().is_symlink() {
+    FileMode::Link
+  } else if metadata.permissions().mode() & 0o111 != 0 {
+    FileMode::BlobExecutable
+  } else {
+    FileMode::Blob
+  };
+
+  Ok(i32::from(mode))
+}
+
 pub fn find_subtree(tree: &Tree, name: &str) -> Option<Oid> {
   for entry in tree.iter() {
     let raw_name = entry.name().unwrap_or("[???]");
@@ -527,40 +1036,85 @@ pub fn find_subtree(tree: &Tree, name: &str) -> Option<Oid> {
   None
 }
 
-pub fn get_status_string(status: Status) -> String {
-  let index_string = if status.is_index_new() {
+pub fn get_submodule_status_string(status: SubmoduleStatus) -> String {
+  let mut flags = Vec::new();
+
+  if status.is_index_added() {
+    flags.push("new".cyan());
+  } else if status.is_index_deleted() {
+    flags.push("del".red());
+  } else if status.is_index_modified() {
+    flags.push("staged".green());
+  }
+
+  if status.is_wd_uninitialized() {
+    flags.push("uninitialized".bright_black());
+  } else if status.is_wd_deleted() {
+    flags.push("missing".bright_red());
+  } else {
+    if status.is_wd_modified() {
+      flags.push("moved".yellow());
+    }
+    if status.contains(SubmoduleStatus::WD_INDEX_MODIFIED) || status.is_wd_wd_modified() {
+      flags.push("dirty".red());
+    }
+    if status.is_wd_untracked() {
+      flags.push("untracked".bright_cyan());
+    }
+  }
+
+  flags
+    .iter()
+    .map(ToString::to_string)
+    .collect::<Vec<_>>()
+    .join(" ")
+}
+
+/// The staged half of a status line: how the index differs from `HEAD`.
+pub fn get_index_status_string(status: Status) -> ColoredString {
+  if status.is_index_new() {
     "new".cyan()
+  } else if status.is_index_renamed() {
+    "ren".blue()
   } else if status.is_index_modified() {
     "mod".green()
   } else if status.is_index_deleted() {
     "del".red()
-  } else if status.is_index_renamed() {
-    "ren".blue()
   } else if status.is_index_typechange() {
     "typ".blue()
   } else {
     "   ".normal()
-  };
+  }
+}
 
-  let working_string = if status.is_wt_new() {
+/// The unstaged half of a status line: how the work tree differs from the
+/// index.
+pub fn get_working_status_string(status: Status) -> ColoredString {
+  if status.is_wt_new() {
     "new".bright_cyan()
+  } else if status.is_wt_renamed() {
+    "ren".bright_blue()
   } else if status.is_wt_modified() {
     "mod".bright_green()
   } else if status.is_wt_deleted() {
     "del".bright_red()
-  } else if status.is_wt_renamed() {
-    "ren".bright_blue()
   } else if status.is_wt_typechange() {
     "typ".bright_blue()
   } else {
     "   ".normal()
-  };
+  }
+}
 
+pub fn get_status_string(status: Status) -> String {
   if status.is_ignored() {
     format!("{}", " ignored".white())
   } else if status.is_conflicted() {
     format!("{}", "conflict".red())
   } else {
-    format!(" {} {}", index_string, working_string)
+    format!(
+      " {} {}",
+      get_index_status_string(status),
+      get_working_status_string(status)
+    )
   }
 }
@@ -0,0 +1,439 @@
+use super::get_index_status_string;
+use super::get_status_string;
+use super::get_working_status_string;
+use super::MilkRepo;
+use colored::*;
+use failure::Error;
+use failure::ResultExt;
+use git2::Blob;
+use git2::Commit;
+use git2::ObjectType;
+use git2::Odb;
+use git2::Oid;
+use git2::Repository;
+use git2::Status;
+use git2::StatusOptions;
+use git2::Tree;
+use git2::TreeEntry;
+use git2::TreeWalkMode;
+use git2::TreeWalkResult;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// What `milk ls` should show for each entry.
+#[derive(Default)]
+pub struct ListOptions {
+  pub recursive: bool,
+  pub long: bool,
+  pub disk_usage: bool,
+  pub status: bool,
+}
+
+/// One entry of a listing, with its path relative to the listed tree.
+struct Row {
+  path: String,
+  kind: Option<ObjectType>,
+  mode: i32,
+  id: Oid,
+  size: Option<u64>,
+  // only in the index or work tree, not in the listed tree
+  added: bool,
+}
+
+fn collect_rows(tree: &Tree, recursive: bool) -> Result<Vec<Row>, Error> {
+  let mut rows = Vec::new();
+  let mut push = |root: &str, entry: &TreeEntry| {
+    rows.push(Row {
+      path: format!("{}{}", root, entry.name().unwrap_or("[invalid utf-8]")),
+      kind: entry.kind(),
+      mode: entry.filemode(),
+      id: entry.id(),
+      size: None,
+      added: false,
+    });
+  };
+
+  if recursive {
+    tree
+      .walk(TreeWalkMode::PreOrder, |root, entry| {
+        push(root, entry);
+        TreeWalkResult::Ok
+      })
+      .with_context(|_| "couldn't walk tree")?;
+  } else {
+    for entry in tree.iter() {
+      push("", &entry);
+    }
+  }
+
+  Ok(rows)
+}
+
+fn blob_size(odb: &Odb, id: Oid) -> Result<u64, Error> {
+  let (size, _) = odb
+    .read_header(id)
+    .with_context(|_| "couldn't read object header")?;
+  Ok(size as u64)
+}
+
+// total size of every blob under a tree. identical subtrees are common across
+// a repository, so sizes are remembered by id
+fn tree_size(
+  repo: &Repository,
+  odb: &Odb,
+  id: Oid,
+  sizes: &mut HashMap<Oid, u64>,
+) -> Result<u64, Error> {
+  if let Some(size) = sizes.get(&id) {
+    return Ok(*size);
+  }
+
+  let tree = repo.find_tree(id).with_context(|_| "couldn't find tree")?;
+  let mut total = 0;
+  for entry in tree.iter() {
+    total += match entry.kind() {
+      Some(ObjectType::Blob) => blob_size(odb, entry.id())?,
+      Some(ObjectType::Tree) => tree_size(repo, odb, entry.id(), sizes)?,
+      _ => 0,
+    };
+  }
+
+  sizes.insert(id, total);
+  Ok(total)
+}
+
+fn entry_id(tree: &Tree, path: &Path) -> Option<Oid> {
+  tree.get_path(path).ok().map(|entry| entry.id())
+}
+
+/// Finds the commit that last changed each of `paths`, starting from `start`.
+/// History is simplified the same way `git log -- <path>` does it: a merge
+/// that took a path unchanged from one of its parents is followed down that
+/// parent instead of being blamed for it.
+fn last_commits(start: &Commit, paths: Vec<PathBuf>) -> Result<HashMap<PathBuf, Oid>, Error> {
+  let mut found = HashMap::new();
+  let mut pending = vec![(start.clone(), paths)];
+
+  while let Some((commit, paths)) = pending.pop() {
+    let tree = commit.tree().with_context(|_| "couldn't find tree")?;
+    let parents = commit
+      .parents()
+      .map(|parent| parent.tree())
+      .collect::<Result<Vec<_>, _>>()
+      .with_context(|_| "couldn't find parent tree")?;
+
+    let mut follow = vec![Vec::new(); parents.len()];
+    for path in paths {
+      let id = entry_id(&tree, &path);
+      match parents
+        .iter()
+        .position(|parent| entry_id(parent, &path) == id)
+      {
+        Some(i) => follow[i].push(path),
+        None => {
+          found.insert(path, commit.id());
+        }
+      }
+    }
+
+    for (parent, paths) in commit.parents().zip(follow) {
+      if !paths.is_empty() {
+        pending.push((parent, paths));
+      }
+    }
+  }
+
+  Ok(found)
+}
+
+/// Reads the index and work tree state of every changed path, keyed by its
+/// path from the root of the repository.
+pub fn work_tree_statuses(repo: &Repository) -> Result<HashMap<PathBuf, Status>, Error> {
+  let mut status_opts = StatusOptions::new();
+  status_opts.include_untracked(true);
+
+  let statuses = repo
+    .statuses(Some(&mut status_opts))
+    .with_context(|_| "couldn't open status")?;
+
+  Ok(
+    statuses
+      .iter()
+      .filter_map(|entry| Some((PathBuf::from(entry.path()?), entry.status())))
+      .collect(),
+  )
+}
+
+// a file shows its own status. A directory shows the one kind of change its
+// contents have in each column, or `mix` when they've changed in different
+// ways, and `conflict` if anything inside of it is conflicted
+fn row_status(statuses: &HashMap<PathBuf, Status>, path: &Path, is_tree: bool) -> String {
+  if !is_tree {
+    return get_status_string(statuses.get(path).cloned().unwrap_or(Status::CURRENT));
+  }
+
+  let changes: Vec<Status> = statuses
+    .iter()
+    .filter(|(changed, _)| changed.starts_with(path))
+    .map(|(_, status)| *status)
+    .collect();
+
+  if changes.iter().any(|status| status.is_conflicted()) {
+    return get_status_string(Status::CONFLICTED);
+  }
+
+  let index_changes = Status::INDEX_NEW
+    | Status::INDEX_MODIFIED
+    | Status::INDEX_DELETED
+    | Status::INDEX_RENAMED
+    | Status::INDEX_TYPECHANGE;
+  let working_changes = Status::WT_NEW
+    | Status::WT_MODIFIED
+    | Status::WT_DELETED
+    | Status::WT_RENAMED
+    | Status::WT_TYPECHANGE;
+
+  let index_string = match column_change(&changes, index_changes) {
+    Some(status) => get_index_status_string(status),
+    None => "mix".yellow(),
+  };
+  let working_string = match column_change(&changes, working_changes) {
+    Some(status) => get_working_status_string(status),
+    None => "mix".bright_yellow(),
+  };
+
+  format!(" {} {}", index_string, working_string)
+}
+
+// the single kind of change within `column` shared by everything in `changes`,
+// or None if they disagree
+fn column_change(changes: &[Status], column: Status) -> Option<Status> {
+  let kinds: HashSet<Status> = changes
+    .iter()
+    .map(|status| *status & column)
+    .filter(|status| !status.is_empty())
+    .collect();
+
+  match kinds.len() {
+    0 => Some(Status::CURRENT),
+    1 => kinds.into_iter().next(),
+    _ => None,
+  }
+}
+
+// rows for paths that are new in the index or work tree, so they show up next
+// to the entries that were already committed
+fn added_rows(
+  rows: &[Row],
+  statuses: &HashMap<PathBuf, Status>,
+  base: &Path,
+  recursive: bool,
+) -> Vec<Row> {
+  let mut seen: HashSet<_> = rows.iter().map(|row| PathBuf::from(&row.path)).collect();
+  let mut added = Vec::new();
+
+  for path in statuses.keys() {
+    let relative = match path.strip_prefix(base) {
+      Ok(relative) if relative != Path::new("") => relative,
+      _ => continue,
+    };
+
+    // untracked directories are reported as a single path ending in a slash
+    let (shown, is_tree) = if recursive {
+      let is_tree = path.to_string_lossy().ends_with('/');
+      (relative.to_path_buf(), is_tree)
+    } else {
+      let mut components = relative.components();
+      let first = PathBuf::from(components.next().unwrap().as_os_str());
+      (
+        first,
+        components.next().is_some() || path.to_string_lossy().ends_with('/'),
+      )
+    };
+
+    if seen.insert(shown.clone()) {
+      added.push(Row {
+        path: shown.to_string_lossy().into_owned(),
+        kind: if is_tree {
+          Some(ObjectType::Tree)
+        } else {
+          Some(ObjectType::Blob)
+        },
+        mode: 0,
+        id: Oid::zero(),
+        size: None,
+        added: true,
+      });
+    }
+  }
+
+  added
+}
+
+fn styled_name(row: &Row) -> String {
+  match row.kind {
+    Some(ObjectType::Tree) => format!("{}/", row.path.blue()),
+    Some(ObjectType::Commit) => format!("@{}", row.path.bright_red()),
+    Some(ObjectType::Tag) => format!("#{}", row.path.bright_cyan()),
+    _ => row.path.clone(),
+  }
+}
+
+/// Prints the entries of `tree`, which sits at `base` inside `commit`.
+pub fn print_listing(
+  repo: &Repository,
+  commit: &Commit,
+  tree: &Tree,
+  base: &Path,
+  options: &ListOptions,
+) -> Result<(), Error> {
+  let mut rows = collect_rows(tree, options.recursive)?;
+
+  let statuses = if options.status {
+    let statuses = work_tree_statuses(repo)?;
+    let added = added_rows(&rows, &statuses, base, options.recursive);
+    if !added.is_empty() {
+      rows.extend(added);
+      // same order git keeps trees in, where directories sort with a slash
+      rows.sort_by_key(|row| match row.kind {
+        Some(ObjectType::Tree) => format!("{}/", row.path),
+        _ => row.path.clone(),
+      });
+    }
+    statuses
+  } else {
+    HashMap::new()
+  };
+  let odb = repo
+    .odb()
+    .with_context(|_| "couldn't open object database")?;
+  let mut sizes = HashMap::new();
+
+  if options.long || options.disk_usage {
+    for row in &mut rows {
+      row.size = match row.kind {
+        Some(ObjectType::Blob) if !row.added => Some(blob_size(&odb, row.id)?),
+        Some(ObjectType::Tree) if options.disk_usage && !row.added => {
+          Some(tree_size(repo, &odb, row.id, &mut sizes)?)
+        }
+        _ => None,
+      };
+    }
+  }
+
+  let commits = if options.long {
+    let paths = rows
+      .iter()
+      .filter(|row| !row.added)
+      .map(|row| base.join(&row.path))
+      .collect();
+    last_commits(commit, paths)?
+  } else {
+    HashMap::new()
+  };
+
+  let size_width = rows
+    .iter()
+    .filter_map(|row| row.size)
+    .map(|size| size.to_string().len())
+    .max()
+    .unwrap_or(1);
+
+  for row in &rows {
+    if options.status {
+      let is_tree = row.kind == Some(ObjectType::Tree);
+      print!("{} ", row_status(&statuses, &base.join(&row.path), is_tree));
+    }
+
+    let size = row
+      .size
+      .map(|size| size.to_string())
+      .unwrap_or_else(|| "-".to_string());
+
+    if options.long && row.added {
+      println!(
+        "{:6} {:6} {:>width$} {} {}",
+        "-",
+        "-",
+        size,
+        "-".bright_black(),
+        styled_name(row),
+        width = size_width
+      );
+    } else if options.long {
+      let kind = row.kind.map(|kind| kind.str()).unwrap_or("?");
+      let last_commit = match commits.get(&base.join(&row.path)) {
+        Some(id) => repo.get_short_id(*id),
+        None => "-".to_string(),
+      };
+      println!(
+        "{:06o} {:6} {:>width$} {} {}",
+        row.mode,
+        kind,
+        size,
+        last_commit.bright_black(),
+        styled_name(row),
+        width = size_width
+      );
+    } else if options.disk_usage {
+      println!("{:>width$} {}", size, styled_name(row), width = size_width);
+    } else if row.added {
+      println!("{}", styled_name(row));
+    } else {
+      println!(
+        "{} {}",
+        styled_name(row),
+        repo.get_short_id(row.id).bright_black()
+      );
+    }
+  }
+
+  if options.disk_usage {
+    let total = tree_size(repo, &odb, tree.id(), &mut sizes)?;
+    println!("{} {} bytes", "total".cyan(), total);
+  }
+
+  Ok(())
+}
+
+/// Prints what's known about a single file in `commit`: its mode, size, type
+/// and the commit that last changed it, plus its work tree state if given.
+pub fn print_file(
+  repo: &Repository,
+  commit: &Commit,
+  entry: &TreeEntry,
+  blob: &Blob,
+  path: &Path,
+  status: Option<Status>,
+) -> Result<(), Error> {
+  println!(
+    "{} {}",
+    entry.name().unwrap_or("[invalid utf-8]"),
+    repo.get_short_id(entry.id()).bright_black()
+  );
+  println!("{} {:06o}", "mode".cyan(), entry.filemode());
+  repo.print_blob_summary(blob);
+
+  match status {
+    Some(Status::CURRENT) => println!("{} clean", "status".cyan()),
+    Some(status) => println!("{} {}", "status".cyan(), get_status_string(status).trim()),
+    None => {}
+  }
+
+  let commits = last_commits(commit, vec![path.to_path_buf()])?;
+  if let Some(id) = commits.get(path) {
+    let last = repo
+      .find_commit(*id)
+      .with_context(|_| "couldn't find commit")?;
+    println!(
+      "{} {} {}",
+      "commit".cyan(),
+      repo.get_short_id(*id).bright_black(),
+      last.summary().unwrap_or("")
+    );
+  }
+
+  Ok(())
+}
@@ -0,0 +1,113 @@
+use failure::Error;
+use failure::ResultExt;
+use git2::Config;
+use std::env;
+use std::io;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::process::Child;
+use std::process::Command;
+use std::process::Stdio;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+const DEFAULT_PAGER: &str = "less -R";
+
+static PAGING: AtomicBool = AtomicBool::new(false);
+
+/// Output is going to a human if stdout is a terminal or if it's being piped
+/// into a pager that we started ourselves.
+pub fn stdout_is_tty() -> bool {
+  PAGING.load(Ordering::SeqCst) || atty::is(atty::Stream::Stdout)
+}
+
+/// Keeps the pager alive until it's dropped, at which point our end of the
+/// pipe is closed and we wait for the user to quit the pager.
+pub struct Pager {
+  child: Child,
+}
+
+impl Drop for Pager {
+  fn drop(&mut self) {
+    let _ = io::stdout().flush();
+    unsafe {
+      libc::close(libc::STDOUT_FILENO);
+    }
+    let _ = self.child.wait();
+  }
+}
+
+/// Works out which pager to use for `command`, following git: `pager.<cmd>`
+/// may turn paging off or name a different pager, then `core.pager`, then
+/// `$PAGER`, then `less -R`.
+fn pager_command(config: &Config, command: &str) -> Option<String> {
+  let per_command = format!("pager.{}", command);
+  if let Ok(enabled) = config.get_bool(&per_command) {
+    if !enabled {
+      return None;
+    }
+  } else if let Ok(pager) = config.get_string(&per_command) {
+    return Some(pager);
+  }
+
+  let pager = config
+    .get_string("core.pager")
+    .ok()
+    .or_else(|| env::var("PAGER").ok())
+    .unwrap_or_else(|| DEFAULT_PAGER.to_string());
+
+  match pager.trim() {
+    "" | "cat" => None,
+    pager => Some(pager.to_string()),
+  }
+}
+
+/// Redirects stdout into a pager when stdout is a terminal. The returned
+/// guard must be kept around until all output has been written.
+pub fn start(config: &Config, command: &str) -> Result<Option<Pager>, Error> {
+  if !atty::is(atty::Stream::Stdout) {
+    return Ok(None);
+  }
+
+  // ask colored while stdout is still the terminal, so NO_COLOR and
+  // CLICOLOR=0 keep working once it's been swapped for the pager's pipe
+  let colorize = colored::control::SHOULD_COLORIZE.should_colorize();
+
+  let pager = match pager_command(config, command) {
+    Some(pager) => pager,
+    None => return Ok(None),
+  };
+
+  let mut pager_process = Command::new("sh");
+  pager_process.arg("-c").arg(&pager).stdin(Stdio::piped());
+
+  // same defaults as git: quit if it fits on one screen, pass colors through,
+  // and don't clear the screen on exit
+  if env::var_os("LESS").is_none() {
+    pager_process.env("LESS", "FRX");
+  }
+
+  let mut child = pager_process
+    .spawn()
+    .with_context(|_| format!("couldn't start pager `{}`", pager))?;
+
+  let pipe = child
+    .stdin
+    .take()
+    .ok_or_else(|| failure::err_msg("couldn't open pager input"))?;
+
+  unsafe {
+    libc::dup2(pipe.as_raw_fd(), libc::STDOUT_FILENO);
+
+    // quitting the pager early should quietly end milk instead of making
+    // every println! panic about a broken pipe
+    libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+  }
+  drop(pipe);
+
+  // our stdout is no longer a terminal, but the pager is showing it on one
+  colored::control::set_override(colorize);
+  PAGING.store(true, Ordering::SeqCst);
+
+  Ok(Some(Pager { child }))
+}
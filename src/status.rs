@@ -0,0 +1,1155 @@
+use super::get_status_string;
+use super::MilkRepo;
+use super::RenameDetection;
+use colored::*;
+use failure::Error;
+use failure::ResultExt;
+use git2::Branch;
+use git2::Delta;
+use git2::Diff;
+use git2::DiffDelta;
+use git2::DiffOptions;
+use git2::ErrorCode;
+use git2::ObjectType;
+use git2::Oid;
+use git2::Repository;
+use git2::RepositoryState;
+use git2::Status;
+use git2::SubmoduleIgnore;
+use git2::SubmoduleStatus;
+use notify::DebouncedEvent;
+use notify::RecommendedWatcher;
+use notify::RecursiveMode;
+use notify::Watcher;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Duration;
+
+// changes that live in the index
+const STAGED: Status = Status::from_bits_truncate(
+  Status::INDEX_NEW.bits()
+    | Status::INDEX_MODIFIED.bits()
+    | Status::INDEX_DELETED.bits()
+    | Status::INDEX_RENAMED.bits()
+    | Status::INDEX_TYPECHANGE.bits(),
+);
+
+// changes to tracked files that only live in the work tree
+const UNSTAGED: Status = Status::from_bits_truncate(
+  Status::WT_MODIFIED.bits()
+    | Status::WT_DELETED.bits()
+    | Status::WT_RENAMED.bits()
+    | Status::WT_TYPECHANGE.bits(),
+);
+
+/// One side of a file's change: HEAD to the index, or the index to the work
+/// tree.
+pub struct FileDelta {
+  pub status: Delta,
+  pub old_path: Vec<u8>,
+  pub new_path: Vec<u8>,
+}
+
+/// A file with changes in the index, the work tree or both.
+pub struct FileStatus {
+  pub status: Status,
+  pub head_to_index: Option<FileDelta>,
+  pub index_to_workdir: Option<FileDelta>,
+}
+
+impl FileStatus {
+  /// The path the file had in HEAD, or in the index if it's new there.
+  /// Copies are new files, so they go by where they were copied to.
+  pub fn path(&self) -> &[u8] {
+    match (&self.head_to_index, &self.index_to_workdir) {
+      (Some(delta), _) | (None, Some(delta)) if delta.status == Delta::Copied => &delta.new_path,
+      (Some(delta), _) | (None, Some(delta)) => &delta.old_path,
+      (None, None) => &[],
+    }
+  }
+
+  // the path the file ends up at, which is what the list is sorted by
+  fn current_path(&self) -> &[u8] {
+    match (&self.index_to_workdir, &self.head_to_index) {
+      (Some(delta), _) | (None, Some(delta)) => &delta.new_path,
+      (None, None) => &[],
+    }
+  }
+}
+
+fn index_status(delta: &DiffDelta) -> Status {
+  match delta.status() {
+    Delta::Added | Delta::Copied => Status::INDEX_NEW,
+    Delta::Deleted => Status::INDEX_DELETED,
+    Delta::Modified => Status::INDEX_MODIFIED,
+    Delta::Renamed if delta.old_file().id() != delta.new_file().id() => {
+      Status::INDEX_RENAMED | Status::INDEX_MODIFIED
+    }
+    Delta::Renamed => Status::INDEX_RENAMED,
+    Delta::Typechange => Status::INDEX_TYPECHANGE,
+    Delta::Conflicted => Status::CONFLICTED,
+    _ => Status::CURRENT,
+  }
+}
+
+fn workdir_status(repo: &Repository, delta: &DiffDelta) -> Status {
+  match delta.status() {
+    Delta::Added | Delta::Copied | Delta::Untracked => Status::WT_NEW,
+    Delta::Deleted => Status::WT_DELETED,
+    Delta::Modified => Status::WT_MODIFIED,
+    Delta::Ignored => Status::IGNORED,
+    Delta::Renamed => {
+      // work tree files don't always have their ids worked out yet
+      let mut new_id = delta.new_file().id();
+      if new_id.is_zero() {
+        let path = delta.new_file().path();
+        if let (Some(workdir), Some(path)) = (repo.workdir(), path) {
+          new_id = Oid::hash_file(ObjectType::Blob, workdir.join(path)).unwrap_or(new_id);
+        }
+      }
+
+      if delta.old_file().id() == new_id {
+        Status::WT_RENAMED
+      } else {
+        Status::WT_RENAMED | Status::WT_MODIFIED
+      }
+    }
+    Delta::Typechange => Status::WT_TYPECHANGE,
+    Delta::Conflicted => Status::CONFLICTED,
+    _ => Status::CURRENT,
+  }
+}
+
+fn file_deltas(diff: &Diff, to_status: impl Fn(&DiffDelta) -> Status) -> Vec<(FileDelta, Status)> {
+  diff
+    .deltas()
+    .map(|delta| {
+      let file_delta = FileDelta {
+        status: delta.status(),
+        old_path: delta.old_file().path_bytes().unwrap_or(b"").to_vec(),
+        new_path: delta.new_file().path_bytes().unwrap_or(b"").to_vec(),
+      };
+      (file_delta, to_status(&delta))
+    })
+    .collect()
+}
+
+/// Works out what changed between HEAD, the index and the work tree. This is
+/// what libgit2's status does, except that renames and copies are found with
+/// `renames` instead of its fixed defaults.
+pub fn read_statuses(
+  repo: &Repository,
+  include_untracked: bool,
+  include_ignored: bool,
+  renames: &RenameDetection,
+) -> Result<Vec<FileStatus>, Error> {
+  let mut index = repo.index().with_context(|_| "couldn't read index")?;
+  // another process may have staged something since the index was opened
+  let _ = index.read(false);
+
+  let head = match repo.head() {
+    Ok(head) => Some(
+      head
+        .peel_to_tree()
+        .with_context(|_| "couldn't peel HEAD to a tree")?,
+    ),
+    Err(ref err) if err.code() == ErrorCode::UnbornBranch || err.code() == ErrorCode::NotFound => {
+      None
+    }
+    Err(err) => return Err(Error::from(err).context("couldn't locate HEAD").into()),
+  };
+
+  let mut diff_opts = DiffOptions::new();
+  diff_opts.include_typechange(true);
+  diff_opts.include_untracked(include_untracked);
+  diff_opts.include_ignored(include_ignored);
+
+  let mut head_to_index = repo
+    .diff_tree_to_index(head.as_ref(), Some(&index), Some(&mut diff_opts))
+    .with_context(|_| "couldn't compare HEAD with the index")?;
+  let mut index_to_workdir = repo
+    .diff_index_to_workdir(Some(&index), Some(&mut diff_opts))
+    .with_context(|_| "couldn't compare the index with the work tree")?;
+
+  if let Some(mut find_options) = renames.find_options() {
+    // untracked files can be where something was renamed to, as in git
+    find_options.for_untracked(true);
+    head_to_index
+      .find_similar(Some(&mut find_options))
+      .with_context(|_| "couldn't detect renames")?;
+    index_to_workdir
+      .find_similar(Some(&mut find_options))
+      .with_context(|_| "couldn't detect renames")?;
+  }
+
+  // the two sides meet at the index, so pair them up by their index paths
+  let mut staged = file_deltas(&head_to_index, index_status);
+  staged.sort_by(|(a, _), (b, _)| a.new_path.cmp(&b.new_path));
+  let mut unstaged = file_deltas(&index_to_workdir, |delta| workdir_status(repo, delta));
+  unstaged.sort_by(|(a, _), (b, _)| a.old_path.cmp(&b.old_path));
+
+  let mut statuses = Vec::new();
+  let mut staged = staged.into_iter().peekable();
+  let mut unstaged = unstaged.into_iter().peekable();
+  loop {
+    let order = match (staged.peek(), unstaged.peek()) {
+      (Some((staged, _)), Some((unstaged, _))) => staged.new_path.cmp(&unstaged.old_path),
+      (Some(_), None) => std::cmp::Ordering::Less,
+      (None, Some(_)) => std::cmp::Ordering::Greater,
+      (None, None) => break,
+    };
+
+    let (head_to_index, index_to_workdir) = match order {
+      std::cmp::Ordering::Less => (staged.next(), None),
+      std::cmp::Ordering::Greater => (None, unstaged.next()),
+      std::cmp::Ordering::Equal => (staged.next(), unstaged.next()),
+    };
+
+    let mut status = Status::CURRENT;
+    for (_, side) in head_to_index.iter().chain(index_to_workdir.iter()) {
+      status |= *side;
+    }
+
+    statuses.push(FileStatus {
+      status,
+      head_to_index: head_to_index.map(|(delta, _)| delta),
+      index_to_workdir: index_to_workdir.map(|(delta, _)| delta),
+    });
+  }
+
+  statuses.sort_by(|a, b| a.current_path().cmp(b.current_path()));
+  Ok(statuses)
+}
+
+/// How many files have changes in each place, for the status header.
+#[derive(Default)]
+pub struct StatusCounts {
+  pub staged: usize,
+  pub unstaged: usize,
+  pub untracked: usize,
+  pub conflicted: usize,
+}
+
+impl StatusCounts {
+  pub fn from_statuses(statuses: &[FileStatus]) -> StatusCounts {
+    let mut counts = StatusCounts::default();
+    for entry in statuses {
+      let status = entry.status;
+      if status.is_conflicted() {
+        counts.conflicted += 1;
+        continue;
+      }
+      if status.intersects(STAGED) {
+        counts.staged += 1;
+      }
+      if status.intersects(UNSTAGED) {
+        counts.unstaged += 1;
+      }
+      if status.is_wt_new() {
+        counts.untracked += 1;
+      }
+    }
+
+    counts
+  }
+}
+
+impl std::fmt::Display for StatusCounts {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    let mut parts = Vec::new();
+    if self.conflicted > 0 {
+      parts.push(format!("{} conflicted", self.conflicted).red());
+    }
+    if self.staged > 0 {
+      parts.push(format!("{} staged", self.staged).green());
+    }
+    if self.unstaged > 0 {
+      parts.push(format!("{} unstaged", self.unstaged).bright_green());
+    }
+    if self.untracked > 0 {
+      parts.push(format!("{} untracked", self.untracked).bright_cyan());
+    }
+
+    if parts.is_empty() {
+      return write!(f, "{}", "clean".white());
+    }
+
+    let parts: Vec<_> = parts.iter().map(ToString::to_string).collect();
+    write!(f, "{}", parts.join(", "))
+  }
+}
+
+/// Describes how far a branch has diverged from its upstream.
+pub fn describe_distance(ahead: usize, behind: usize) -> String {
+  match (ahead, behind) {
+    (0, 0) => "up to date".white().to_string(),
+    (ahead, 0) => format!("ahead {}", ahead).green().to_string(),
+    (0, behind) => format!("behind {}", behind).red().to_string(),
+    (ahead, behind) => format!(
+      "{}, {}",
+      format!("ahead {}", ahead).green(),
+      format!("behind {}", behind).red()
+    ),
+  }
+}
+
+// describes where HEAD is: a branch and its upstream, a detached commit, or a
+// branch that doesn't have any commits yet
+fn describe_head(repo: &Repository) -> Result<String, Error> {
+  let head = match repo.head() {
+    Ok(head) => head,
+    Err(ref err) if err.code() == ErrorCode::UnbornBranch => {
+      let head = repo
+        .find_reference("HEAD")
+        .with_context(|_| "couldn't locate HEAD")?;
+      let target = head
+        .symbolic_target()
+        .unwrap_or("[???]")
+        .trim_start_matches("refs/heads/");
+      return Ok(format!(
+        "{} {}",
+        target.cyan(),
+        "no commits yet".bright_black()
+      ));
+    }
+    Err(err) => return Err(Error::from(err).context("couldn't locate HEAD").into()),
+  };
+
+  let oid = head
+    .target()
+    .ok_or_else(|| failure::err_msg("couldn't resolve HEAD"))?;
+
+  if !head.is_branch() {
+    return Ok(format!(
+      "{} {}",
+      repo.highlight_named_oid("HEAD", oid),
+      "detached".yellow()
+    ));
+  }
+
+  let name = head.shorthand().unwrap_or("[???]");
+  let mut description = repo.highlight_named_oid(name, oid);
+
+  let upstream = match Branch::wrap(head).upstream() {
+    Ok(upstream) => upstream,
+    Err(_) => return Ok(description),
+  };
+
+  let upstream_name = upstream.name().ok().flatten().unwrap_or("[???]");
+  description.push_str(&format!(" -> {}", upstream_name.cyan()));
+
+  if let Some(upstream_oid) = upstream.get().target() {
+    let (ahead, behind) = repo
+      .graph_ahead_behind(oid, upstream_oid)
+      .with_context(|_| "couldn't compare with upstream")?;
+
+    description.push_str(&format!(" {}", describe_distance(ahead, behind)));
+  }
+
+  Ok(description)
+}
+
+/// Prints the branch we're on, how it compares to its upstream, and how many
+/// files have changes.
+pub fn print_header(repo: &Repository, statuses: &[FileStatus]) -> Result<(), Error> {
+  println!("{}", describe_head(repo)?);
+  println!("{}", StatusCounts::from_statuses(statuses));
+  Ok(())
+}
+
+/// A changed file as shown by `milk status`.
+pub struct Change {
+  pub status: Status,
+  /// Where the file is now.
+  pub path: String,
+  /// Where the file used to be, following renames in the index and then in
+  /// the work tree.
+  pub renamed_from: Vec<String>,
+  /// What the file is a copy of, when copies are being looked for.
+  pub copied_from: Option<String>,
+  /// The summary line, if this is a submodule.
+  pub submodule: Option<String>,
+}
+
+impl Change {
+  fn describe(&self, name: &str) -> String {
+    if let Some(summary) = &self.submodule {
+      return format!("@{} {}", name.bright_red(), summary);
+    }
+
+    if let Some(from) = &self.copied_from {
+      let from = format!("(copy of {})", from);
+      format!("{} {}", name, from.bright_black())
+    } else if self.renamed_from.is_empty() {
+      name.to_string()
+    } else {
+      format!("{} -> {}", self.renamed_from.join(" -> "), name)
+    }
+  }
+
+  // in a tree, the name alone doesn't say where a renamed file came from, so
+  // spell out the full old path afterwards
+  fn describe_leaf(&self, name: &str) -> String {
+    if self.submodule.is_some() || self.renamed_from.is_empty() {
+      return self.describe(name);
+    }
+
+    let from = format!("(from {})", self.renamed_from.join(" -> "));
+    format!("{} {}", name, from.bright_black())
+  }
+}
+
+// a file can be renamed in the index and then again in the work tree, so this
+// follows the whole chain
+fn rename_chain(entry: &FileStatus) -> Option<Vec<String>> {
+  let status = entry.status;
+  let mut paths = Vec::new();
+
+  if status.is_index_renamed() {
+    let delta = entry.head_to_index.as_ref()?;
+    paths.push(&delta.old_path);
+    paths.push(&delta.new_path);
+  }
+
+  if status.is_wt_renamed() {
+    let delta = entry.index_to_workdir.as_ref()?;
+    if paths.is_empty() {
+      paths.push(&delta.old_path);
+    }
+    paths.push(&delta.new_path);
+  }
+
+  if paths.is_empty() {
+    return None;
+  }
+
+  Some(
+    paths
+      .iter()
+      .map(|path| String::from_utf8_lossy(path).into_owned())
+      .collect(),
+  )
+}
+
+// copies only turn up when they're asked for, and then only as the new file
+fn copy_source(entry: &FileStatus) -> Option<String> {
+  let delta = [&entry.head_to_index, &entry.index_to_workdir]
+    .iter()
+    .filter_map(|delta| delta.as_ref())
+    .find(|delta| delta.status == Delta::Copied)?;
+  Some(String::from_utf8_lossy(&delta.old_path).into_owned())
+}
+
+pub fn collect_changes(repo: &Repository, statuses: &[FileStatus]) -> Result<Vec<Change>, Error> {
+  let submodules = repo
+    .submodules()
+    .with_context(|_| "couldn't list submodules")?;
+  let submodules: HashMap<_, _> = submodules
+    .iter()
+    .map(|submodule| (submodule.path().to_path_buf(), submodule))
+    .collect();
+
+  let mut changes = Vec::new();
+  for entry in statuses {
+    let path = std::str::from_utf8(entry.path())
+      .unwrap_or("[invalid utf-8]")
+      .to_string();
+
+    if let Some(mut chain) = rename_chain(entry) {
+      let path = chain.pop().unwrap_or(path);
+      changes.push(Change {
+        status: entry.status,
+        path,
+        renamed_from: chain,
+        copied_from: None,
+        submodule: None,
+      });
+      continue;
+    }
+
+    if let Some(from) = copy_source(entry) {
+      changes.push(Change {
+        status: entry.status,
+        path,
+        renamed_from: Vec::new(),
+        copied_from: Some(from),
+        submodule: None,
+      });
+      continue;
+    }
+
+    let submodule = match submodules.get(Path::new(&path)) {
+      Some(submodule) => Some(repo.get_submodule_summary(submodule)?),
+      None => None,
+    };
+
+    changes.push(Change {
+      status: entry.status,
+      path,
+      renamed_from: Vec::new(),
+      copied_from: None,
+      submodule,
+    });
+  }
+
+  Ok(changes)
+}
+
+/// Prints one line per changed file.
+pub fn print_flat<'a>(changes: impl IntoIterator<Item = &'a Change>) {
+  for change in changes {
+    println!(
+      "{} {}",
+      get_status_string(change.status),
+      change.describe(&change.path)
+    );
+  }
+}
+
+/// Prints changes split up by where they live. Files with both staged and
+/// unstaged changes show up in both sections.
+pub fn print_grouped(changes: &[Change]) {
+  let sections: &[(ColoredString, &dyn Fn(Status) -> bool)] = &[
+    ("conflicted".red(), &|status: Status| status.is_conflicted()),
+    ("staged".green(), &|status: Status| {
+      !status.is_conflicted() && status.intersects(STAGED)
+    }),
+    ("unstaged".bright_green(), &|status: Status| {
+      !status.is_conflicted() && status.intersects(UNSTAGED)
+    }),
+    ("untracked".bright_cyan(), &|status: Status| {
+      status.is_wt_new()
+    }),
+    ("ignored".white(), &|status: Status| status.is_ignored()),
+  ];
+
+  let mut first = true;
+  for (title, belongs) in sections {
+    let members: Vec<_> = changes
+      .iter()
+      .filter(|change| belongs(change.status))
+      .collect();
+    if members.is_empty() {
+      continue;
+    }
+
+    if !first {
+      println!();
+    }
+    first = false;
+
+    println!("{}", title.clone().bold());
+    print_flat(members);
+  }
+}
+
+#[derive(Default)]
+struct TreeNode<'a> {
+  children: BTreeMap<String, TreeNode<'a>>,
+  change: Option<&'a Change>,
+}
+
+impl<'a> TreeNode<'a> {
+  fn insert(&mut self, change: &'a Change) {
+    // untracked directories come back from libgit2 with a trailing slash
+    let path = change.path.trim_end_matches('/');
+    let mut node = self;
+    for component in path.split('/') {
+      node = node.children.entry(component.to_string()).or_default();
+    }
+    node.change = Some(change);
+  }
+
+  fn print(&self, prefix: &str) {
+    let count = self.children.len();
+    for (i, (name, child)) in self.children.iter().enumerate() {
+      let last = i + 1 == count;
+      let branch = if last { "└── " } else { "├── " };
+
+      // directories that only hold one other directory get folded into it
+      let mut name = name.clone();
+      let mut child = child;
+      while child.change.is_none() && child.children.len() == 1 {
+        let (grandchild_name, grandchild) = child.children.iter().next().unwrap();
+        if grandchild.change.is_some() {
+          break;
+        }
+        name = format!("{}/{}", name, grandchild_name);
+        child = grandchild;
+      }
+
+      match child.change {
+        Some(change) => {
+          let mut name = name.clone();
+          if change.path.ends_with('/') {
+            name.push('/');
+          }
+          println!(
+            "{} {}{}{}",
+            get_status_string(change.status),
+            prefix.bright_black(),
+            branch.bright_black(),
+            change.describe_leaf(&name)
+          );
+        }
+        None => println!(
+          "{:8} {}{}{}",
+          "",
+          prefix.bright_black(),
+          branch.bright_black(),
+          format!("{}/", name).blue().bold()
+        ),
+      }
+
+      if !child.children.is_empty() {
+        let extension = if last { "    " } else { "│   " };
+        child.print(&format!("{}{}", prefix, extension));
+      }
+    }
+  }
+}
+
+/// Prints changes as a directory tree, leaving out directories without any
+/// changes in them.
+pub fn print_tree(changes: &[Change]) {
+  let mut root = TreeNode::default();
+  for change in changes {
+    root.insert(change);
+  }
+  root.print("");
+}
+
+/// Version of the `--porcelain` format. Bump this whenever a line changes
+/// shape; adding new line kinds that parsers can skip doesn't need a bump.
+pub const PORCELAIN_VERSION: u32 = 1;
+
+fn index_code(status: Status) -> u8 {
+  if status.is_index_new() {
+    b'A'
+  } else if status.is_index_renamed() {
+    b'R'
+  } else if status.is_index_modified() {
+    b'M'
+  } else if status.is_index_deleted() {
+    b'D'
+  } else if status.is_index_typechange() {
+    b'T'
+  } else {
+    b'.'
+  }
+}
+
+fn worktree_code(status: Status) -> u8 {
+  if status.is_wt_renamed() {
+    b'R'
+  } else if status.is_wt_modified() {
+    b'M'
+  } else if status.is_wt_deleted() {
+    b'D'
+  } else if status.is_wt_typechange() {
+    b'T'
+  } else {
+    b'.'
+  }
+}
+
+// `N...` for plain files, otherwise `S` followed by whether the checked out
+// commit moved, whether tracked content is modified, and whether there are
+// untracked files, the same as git's porcelain v2
+fn submodule_code(status: Option<SubmoduleStatus>) -> [u8; 4] {
+  let status = match status {
+    Some(status) => status,
+    None => return *b"N...",
+  };
+
+  let flag = |set: bool, code: u8| if set { code } else { b'.' };
+  [
+    b'S',
+    flag(status.is_wd_modified(), b'C'),
+    flag(
+      status.intersects(SubmoduleStatus::WD_INDEX_MODIFIED | SubmoduleStatus::WD_WD_MODIFIED),
+      b'M',
+    ),
+    flag(status.is_wd_untracked(), b'U'),
+  ]
+}
+
+// without -z, paths that would break the line format are quoted like C
+// strings, which is also what git does
+fn quote_path(path: &[u8]) -> Vec<u8> {
+  let needs_quoting = path
+    .iter()
+    .any(|&c| c < 0x20 || c == b'"' || c == b'\\' || c >= 0x7f);
+  if !needs_quoting {
+    return path.to_vec();
+  }
+
+  let mut quoted = vec![b'"'];
+  for &c in path {
+    match c {
+      b'"' => quoted.extend_from_slice(b"\\\""),
+      b'\\' => quoted.extend_from_slice(b"\\\\"),
+      b'\t' => quoted.extend_from_slice(b"\\t"),
+      b'\n' => quoted.extend_from_slice(b"\\n"),
+      c if !(0x20..0x7f).contains(&c) => {
+        quoted.extend_from_slice(format!("\\{:03o}", c).as_bytes())
+      }
+      c => quoted.push(c),
+    }
+  }
+  quoted.push(b'"');
+  quoted
+}
+
+/// Writes the status in the stable `--porcelain` format described in the
+/// README. Records end with a newline, or with NUL when `nul` is set, in which
+/// case paths are never quoted.
+pub fn print_porcelain(repo: &Repository, statuses: &[FileStatus], nul: bool) -> Result<(), Error> {
+  let terminator = if nul { b'\0' } else { b'\n' };
+  let separator = if nul { b'\0' } else { b'\t' };
+  let path = |path: &[u8]| if nul { path.to_vec() } else { quote_path(path) };
+
+  let index = repo.index().with_context(|_| "couldn't read index")?;
+  let mut conflicts = HashMap::new();
+  for conflict in index
+    .conflicts()
+    .with_context(|_| "couldn't read conflicts")?
+  {
+    let conflict = conflict.with_context(|_| "couldn't read conflict")?;
+    let entry = conflict
+      .our
+      .as_ref()
+      .or(conflict.their.as_ref())
+      .or(conflict.ancestor.as_ref());
+    if let Some(entry) = entry {
+      let stages = [
+        if conflict.ancestor.is_some() {
+          b'1'
+        } else {
+          b'.'
+        },
+        if conflict.our.is_some() { b'2' } else { b'.' },
+        if conflict.their.is_some() { b'3' } else { b'.' },
+      ];
+      conflicts.insert(entry.path.clone(), stages);
+    }
+  }
+
+  let submodules = repo
+    .submodules()
+    .with_context(|_| "couldn't list submodules")?;
+  let submodule_paths: HashMap<_, _> = submodules
+    .iter()
+    .filter_map(|submodule| Some((submodule.path().to_path_buf(), submodule.name()?)))
+    .collect();
+
+  let mut out = Vec::new();
+  out.extend_from_slice(format!("# milk-status {}", PORCELAIN_VERSION).as_bytes());
+  out.push(terminator);
+
+  for entry in statuses {
+    let status = entry.status;
+    let entry_path = entry.path();
+
+    if status.is_conflicted() {
+      let stages = conflicts.get(entry_path).cloned().unwrap_or(*b"...");
+      out.extend_from_slice(b"u ");
+      out.extend_from_slice(&stages);
+      out.push(b' ');
+      out.extend(path(entry_path));
+    } else if status.is_ignored() {
+      out.extend_from_slice(b"! ");
+      out.extend(path(entry_path));
+    } else if status.is_wt_new() && !status.intersects(Status::INDEX_NEW) {
+      out.extend_from_slice(b"? ");
+      out.extend(path(entry_path));
+    } else {
+      let submodule_status = match std::str::from_utf8(entry_path)
+        .ok()
+        .and_then(|p| submodule_paths.get(Path::new(p)))
+      {
+        Some(name) => Some(
+          repo
+            .submodule_status(name, SubmoduleIgnore::Unspecified)
+            .with_context(|_| "couldn't read submodule status")?,
+        ),
+        None => None,
+      };
+
+      let chain = rename_chain(entry);
+      out.push(if chain.is_some() { b'r' } else { b'c' });
+      out.push(b' ');
+      out.push(index_code(status));
+      out.push(worktree_code(status));
+      out.push(b' ');
+      out.extend_from_slice(&submodule_code(submodule_status));
+      out.push(b' ');
+
+      match chain {
+        Some(chain) => {
+          let current = chain.last().cloned().unwrap_or_default();
+          out.extend(path(current.as_bytes()));
+          out.push(separator);
+          out.extend(path(chain[0].as_bytes()));
+        }
+        None => out.extend(path(entry_path)),
+      }
+    }
+
+    out.push(terminator);
+  }
+
+  let stdout = io::stdout();
+  let mut stdout = stdout.lock();
+  stdout
+    .write_all(&out)
+    .with_context(|_| "couldn't write status")?;
+
+  Ok(())
+}
+
+/// An operation that stopped partway and is waiting on the user.
+struct InProgress {
+  name: &'static str,
+  details: Vec<String>,
+  resume: &'static str,
+  abort: &'static str,
+}
+
+// state files live directly in the git dir, one value per line
+fn read_state_file(repo: &Repository, name: &str) -> Option<String> {
+  let contents = fs::read_to_string(repo.path().join(name)).ok()?;
+  let contents = contents.trim();
+  if contents.is_empty() {
+    None
+  } else {
+    Some(contents.to_string())
+  }
+}
+
+fn short_state_id(repo: &Repository, name: &str) -> Option<String> {
+  let contents = read_state_file(repo, name)?;
+  let oid = Oid::from_str(contents.lines().next()?).ok()?;
+  Some(repo.get_short_id(oid))
+}
+
+// rebases keep their progress in rebase-merge/ or rebase-apply/ depending on
+// the backend, with slightly different file names
+fn describe_rebase(repo: &Repository, dir: &str) -> Vec<String> {
+  let file = |name: &str| read_state_file(repo, &format!("{}/{}", dir, name));
+  let mut details = Vec::new();
+
+  let branch = file("head-name")
+    .map(|name| name.trim_start_matches("refs/heads/").to_string())
+    .unwrap_or_else(|| "detached HEAD".to_string());
+  let onto = file("onto")
+    .and_then(|onto| Oid::from_str(&onto).ok())
+    .map(|oid| repo.get_short_id(oid));
+  match onto {
+    Some(onto) => details.push(format!("{} onto {}", branch.cyan(), onto.bright_black())),
+    None => details.push(format!("{}", branch.cyan())),
+  }
+
+  let step = file("msgnum").or_else(|| file("next"));
+  let total = file("end").or_else(|| file("last"));
+  if let (Some(step), Some(total)) = (step, total) {
+    details.push(format!("step {}/{}", step, total));
+  }
+
+  if let Some(stopped) = file("stopped-sha").and_then(|sha| Oid::from_str(&sha).ok()) {
+    details.push(format!(
+      "stopped at {}",
+      repo.get_short_id(stopped).bright_black()
+    ));
+  }
+
+  details
+}
+
+fn in_progress(repo: &Repository) -> Option<InProgress> {
+  let state = match repo.state() {
+    RepositoryState::Clean => return None,
+    state => state,
+  };
+
+  let in_progress = match state {
+    RepositoryState::Merge => {
+      let heads = read_state_file(repo, "MERGE_HEAD").unwrap_or_default();
+      let heads: Vec<_> = heads
+        .lines()
+        .filter_map(|line| Oid::from_str(line).ok())
+        .map(|oid| repo.get_short_id(oid).bright_black().to_string())
+        .collect();
+      let mut details = vec![format!("merging {}", heads.join(", "))];
+      if let Some(message) = read_state_file(repo, "MERGE_MSG") {
+        details.push(message.lines().next().unwrap_or("").to_string());
+      }
+      InProgress {
+        name: "merge",
+        details,
+        resume: "git merge --continue",
+        abort: "git merge --abort",
+      }
+    }
+
+    RepositoryState::Revert | RepositoryState::RevertSequence => InProgress {
+      name: "revert",
+      details: short_state_id(repo, "REVERT_HEAD")
+        .map(|id| vec![format!("reverting {}", id.bright_black())])
+        .unwrap_or_default(),
+      resume: "git revert --continue",
+      abort: "git revert --abort",
+    },
+
+    RepositoryState::CherryPick | RepositoryState::CherryPickSequence => InProgress {
+      name: "cherry-pick",
+      details: short_state_id(repo, "CHERRY_PICK_HEAD")
+        .map(|id| vec![format!("picking {}", id.bright_black())])
+        .unwrap_or_default(),
+      resume: "git cherry-pick --continue",
+      abort: "git cherry-pick --abort",
+    },
+
+    RepositoryState::Bisect => {
+      let mut details = Vec::new();
+      if let Some(start) = read_state_file(repo, "BISECT_START") {
+        details.push(format!("started from {}", start.cyan()));
+      }
+      if let Ok(bad) = repo.refname_to_id("refs/bisect/bad") {
+        details.push(format!("bad {}", repo.get_short_id(bad).bright_black()));
+      }
+      let good = repo
+        .references_glob("refs/bisect/good-*")
+        .map(|refs| refs.count())
+        .unwrap_or(0);
+      if good > 0 {
+        details.push(format!("{} good", good));
+      }
+      InProgress {
+        name: "bisect",
+        details,
+        resume: "git bisect good|bad",
+        abort: "git bisect reset",
+      }
+    }
+
+    RepositoryState::ApplyMailbox => InProgress {
+      name: "am",
+      details: describe_rebase(repo, "rebase-apply"),
+      resume: "git am --continue",
+      abort: "git am --abort",
+    },
+
+    // rebase and friends
+    _ => {
+      let dir = if repo.path().join("rebase-merge").is_dir() {
+        "rebase-merge"
+      } else {
+        "rebase-apply"
+      };
+      InProgress {
+        name: "rebase",
+        details: describe_rebase(repo, dir),
+        resume: "git rebase --continue",
+        abort: "git rebase --abort",
+      }
+    }
+  };
+
+  Some(in_progress)
+}
+
+/// Prints a banner if a merge, rebase, cherry-pick, revert or bisect stopped
+/// partway, saying what's going on and how to get out of it.
+pub fn print_state_banner(repo: &Repository) {
+  let in_progress = match in_progress(repo) {
+    Some(in_progress) => in_progress,
+    None => return,
+  };
+
+  let title = format!(" {} in progress ", in_progress.name.to_uppercase());
+  println!(
+    "{} {}",
+    title.black().on_yellow().bold(),
+    in_progress.details.join(", ")
+  );
+  println!(
+    "{} {}  {} {}",
+    "continue:".bright_black(),
+    in_progress.resume,
+    "abort:".bright_black(),
+    in_progress.abort
+  );
+}
+
+// how long the work tree has to settle before the status gets redrawn, so a
+// big save or checkout only causes one refresh
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(250);
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_interrupt(_signal: libc::c_int) {
+  INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+// changes inside the git dir only matter if they touch the index, and changes
+// to ignored files don't show up in the status at all
+fn event_matters(repo: &Repository, event: &DebouncedEvent) -> bool {
+  let paths = match event {
+    DebouncedEvent::Create(path)
+    | DebouncedEvent::Write(path)
+    | DebouncedEvent::Chmod(path)
+    | DebouncedEvent::Remove(path) => vec![path],
+    DebouncedEvent::Rename(from, to) => vec![from, to],
+    DebouncedEvent::Rescan => return true,
+    _ => return false,
+  };
+
+  paths.iter().any(|path| {
+    if path.starts_with(repo.path()) {
+      return path.file_name() == Some(OsStr::new("index"));
+    }
+
+    match repo
+      .workdir()
+      .and_then(|workdir| path.strip_prefix(workdir).ok())
+    {
+      Some(relative) => !repo.is_path_ignored(relative).unwrap_or(false),
+      None => false,
+    }
+  })
+}
+
+fn redraw<F>(render: &mut F) -> Result<(), Error>
+where
+  F: FnMut() -> Result<(), Error>,
+{
+  // clear the screen and move the cursor back to the top
+  print!("\x1b[2J\x1b[H");
+  render()?;
+  io::stdout()
+    .flush()
+    .with_context(|_| "couldn't write status")?;
+  Ok(())
+}
+
+fn is_watched_dir(repo: &Repository, workdir: &Path, path: &Path) -> bool {
+  if path.file_name() == Some(OsStr::new(".git")) {
+    return false;
+  }
+
+  match path.strip_prefix(workdir) {
+    Ok(relative) => !repo.is_path_ignored(relative).unwrap_or(false),
+    Err(_) => false,
+  }
+}
+
+// watches `dir` and every directory under it that isn't ignored, one at a
+// time, so build output and dependency trees don't use up the inotify watches
+fn watch_tree(
+  watcher: &mut RecommendedWatcher,
+  repo: &Repository,
+  workdir: &Path,
+  dir: &Path,
+) -> Result<(), Error> {
+  watcher
+    .watch(dir, RecursiveMode::NonRecursive)
+    .with_context(|_| format!("couldn't watch {}", dir.display()))?;
+
+  // directories can disappear while they're being walked
+  let entries = match fs::read_dir(dir) {
+    Ok(entries) => entries,
+    Err(_) => return Ok(()),
+  };
+
+  for entry in entries.filter_map(|entry| entry.ok()) {
+    let is_dir = entry
+      .file_type()
+      .map(|file_type| file_type.is_dir())
+      .unwrap_or(false);
+    let path = entry.path();
+    if is_dir && is_watched_dir(repo, workdir, &path) {
+      watch_tree(watcher, repo, workdir, &path)?;
+    }
+  }
+
+  Ok(())
+}
+
+// directories created after watching started need watches of their own
+fn watch_new_dirs(
+  watcher: &mut RecommendedWatcher,
+  repo: &Repository,
+  workdir: &Path,
+  event: &DebouncedEvent,
+) {
+  let path = match event {
+    DebouncedEvent::Create(path) | DebouncedEvent::Rename(_, path) => path,
+    _ => return,
+  };
+
+  if path.is_dir() && is_watched_dir(repo, workdir, path) {
+    // it may already be gone again, which is fine
+    let _ = watch_tree(watcher, repo, workdir, path);
+  }
+}
+
+/// Calls `render` once, then again every time the work tree or the index
+/// changes, until interrupted with Ctrl-C.
+pub fn watch<F>(repo: &Repository, mut render: F) -> Result<(), Error>
+where
+  F: FnMut() -> Result<(), Error>,
+{
+  let workdir = repo
+    .workdir()
+    .ok_or_else(|| failure::err_msg("repository is bare"))?;
+
+  let (tx, rx) = mpsc::channel();
+  let mut watcher =
+    notify::watcher(tx, WATCH_DEBOUNCE).with_context(|_| "couldn't start watching")?;
+  watch_tree(&mut watcher, repo, workdir, workdir)?;
+
+  // git replaces the index by renaming a lock file over it, which a watch on
+  // the index itself wouldn't survive, so the git directory gets one instead
+  watcher
+    .watch(repo.path(), RecursiveMode::NonRecursive)
+    .with_context(|_| "couldn't watch git directory")?;
+
+  unsafe {
+    let handler = handle_interrupt as extern "C" fn(libc::c_int);
+    libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+  }
+
+  redraw(&mut render)?;
+
+  while !INTERRUPTED.load(Ordering::SeqCst) {
+    match rx.recv_timeout(Duration::from_millis(100)) {
+      Ok(event) => {
+        // catch up on everything that's queued so one burst is one redraw
+        let mut changed = false;
+        for event in std::iter::once(event).chain(rx.try_iter()) {
+          watch_new_dirs(&mut watcher, repo, workdir, &event);
+          changed |= event_matters(repo, &event);
+        }
+
+        if changed {
+          redraw(&mut render)?;
+        }
+      }
+      Err(RecvTimeoutError::Timeout) => {}
+      Err(RecvTimeoutError::Disconnected) => break,
+    }
+  }
+
+  println!();
+  Ok(())
+}